@@ -0,0 +1,162 @@
+//! Admin socket reporting the status of the daemon.
+//! Every client gets a single JSON document, then the connection is closed.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use libstore::store::BuildStore;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::stream::StreamExt;
+
+use crate::connections::Connections;
+use crate::error::CommandResult;
+use crate::stats::{OpStats, Stats, LATENCY_BUCKETS};
+
+/// A client which does not read its status in this time is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub pid: u32,
+    pub connections: Vec<ConnectionStatus>,
+    /// Ops served since the daemon started
+    pub ops: BTreeMap<String, OpStats>,
+    /// Upper bounds of `OpStats::buckets` in seconds
+    pub latency_buckets: Vec<f64>,
+    /// Build slots in use
+    pub local_builds: usize,
+    pub store: Option<StoreStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionStatus {
+    pub id: u64,
+    pub uid: u32,
+    pub user: String,
+    pub pid: Option<i32>,
+    pub trusted: bool,
+    /// Seconds since the epoch
+    pub started: u64,
+    pub op: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoreStatus {
+    pub valid_paths: u64,
+    pub nar_size: u64,
+    pub free_space: u64,
+}
+
+pub async fn status(
+    connections: &Connections,
+    stats: &Stats,
+    store: Option<&dyn BuildStore>,
+) -> Status {
+    let connections = connections
+        .list()
+        .into_iter()
+        .map(|v| ConnectionStatus {
+            id: v.id,
+            uid: v.uid,
+            user: v.user,
+            pid: v.pid,
+            trusted: v.trusted,
+            started: v
+                .started
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|v| v.as_secs())
+                .unwrap_or(0),
+            op: v.op,
+        })
+        .collect();
+
+    let store = match store {
+        Some(store) => match store_status(store).await {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("admin: could not query store stats: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    Status {
+        pid: std::process::id(),
+        connections,
+        ops: stats.ops(),
+        latency_buckets: LATENCY_BUCKETS.to_vec(),
+        local_builds: libstore::build::worker::Worker::get_nr_local_builds(),
+        store,
+    }
+}
+
+async fn store_status(store: &dyn BuildStore) -> CommandResult<StoreStatus> {
+    let stats = store.query_store_stats().await?;
+    Ok(StoreStatus {
+        valid_paths: stats.valid_paths,
+        nar_size: stats.nar_size,
+        free_space: stats.free_space,
+    })
+}
+
+/// Answer every client on `listener` with the current status, each in its own task.
+/// Only root and the user running the daemon may connect.
+pub async fn serve(mut listener: UnixListener, connections: Connections, stats: Stats) {
+    // opened with the first client, and again after it failed to open
+    let mut store: Option<Rc<Box<dyn BuildStore>>> = None;
+    while let Some(stream) = listener.next().await {
+        let stream = match stream {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("admin: error accepting connection: {}", e);
+                continue;
+            }
+        };
+        if store.is_none() {
+            let uri = libstore::CONFIG.read().unwrap().store.to_string();
+            match libstore::open_store(&uri, std::collections::HashMap::new()).await {
+                Ok(v) => store = Some(Rc::new(v)),
+                Err(e) => warn!("admin: could not open the store {}: {}", uri, e),
+            }
+        }
+
+        let (connections, stats, store) = (connections.clone(), stats.clone(), store.clone());
+        tokio::task::spawn_local(async move {
+            let store = store.as_deref().map(|v| &**v);
+            if let Err(e) = serve_client(stream, &connections, &stats, store).await {
+                warn!("admin: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_client(
+    mut stream: UnixStream,
+    connections: &Connections,
+    stats: &Stats,
+    store: Option<&dyn BuildStore>,
+) -> CommandResult<()> {
+    let uid = stream.peer_cred()?.uid();
+    if uid != 0 && uid != unsafe { libc::getuid() } {
+        return Err(crate::error::CommandError::DisallowedUser {
+            user: uid.to_string(),
+        });
+    }
+
+    let status = status(connections, stats, store).await;
+
+    let mut json = serde_json::to_vec(&status).map_err(std::io::Error::from)?;
+    json.push(b'\n');
+    tokio::time::timeout(WRITE_TIMEOUT, stream.write_all(&json))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "writing the status timed out")
+        })??;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    Ok(())
+}
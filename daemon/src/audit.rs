@@ -0,0 +1,228 @@
+//! Audit log of the ops changing the store, one JSON object per line
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use libstore::connection::OpReport;
+use libstore::store::protocol::WorkerOp;
+use serde::Serialize;
+
+/// Ops which are written to the audit log
+pub const AUDITED_OPS: [WorkerOp; 6] = [
+    WorkerOp::WopAddToStore,
+    WorkerOp::WopAddToStoreNar,
+    WorkerOp::WopAddTextToStore,
+    WorkerOp::WopBuildPaths,
+    WorkerOp::WopCollectGarbage,
+    WorkerOp::WopAddSignatures,
+];
+
+/// Serializes writes and rotation of all connections of this process
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    /// Rotate once the file would grow beyond this many bytes, 0 disables rotation
+    max_size: u64,
+    /// Number of rotated files to keep
+    keep: usize,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P, max_size: u64, keep: usize) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_size,
+            keep,
+        }
+    }
+
+    /// Returns `None` if no audit log is configured
+    pub fn from_config(config: &libutil::config::NixConfig) -> Option<Self> {
+        if config.daemon_audit_log.is_empty() {
+            return None;
+        }
+        Some(Self::new(
+            &config.daemon_audit_log,
+            config.daemon_audit_log_max_size as u64,
+            config.daemon_audit_log_keep,
+        ))
+    }
+
+    pub fn write(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _lock = LOCK.lock().unwrap();
+        if self.max_size != 0 {
+            match std::fs::metadata(&self.path) {
+                Ok(v) if v.len() != 0 && v.len() + line.len() as u64 > self.max_size => {
+                    self.rotate()?
+                }
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // the file is opened for every line, so rotation by other processes is picked up
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)
+    }
+
+    /// Path of the `n`th rotated file, `n` = 0 is the current log
+    fn rotated(&self, n: usize) -> PathBuf {
+        if n == 0 {
+            return self.path.clone();
+        }
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        trace!("rotating audit log {}", self.path.display());
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+
+        match std::fs::remove_file(self.rotated(self.keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        for n in (0..self.keep).rev() {
+            match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry<'a> {
+    /// RFC 3339 timestamp
+    pub time: String,
+    pub uid: u32,
+    pub user: &'a str,
+    pub pid: Option<i32>,
+    pub trusted: bool,
+    pub op: String,
+    pub paths: &'a [String],
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Writes the audited ops of a connection to the audit log
+pub struct Observer {
+    pub log: AuditLog,
+    pub uid: u32,
+    pub user: String,
+    pub pid: Option<i32>,
+    pub trusted: bool,
+}
+
+impl libstore::connection::Observer for Observer {
+    fn op_started(&self, _op: WorkerOp) {}
+
+    fn op_finished(&self, report: &OpReport) {
+        if !AUDITED_OPS.contains(&report.op) {
+            return;
+        }
+
+        let entry = Entry {
+            time: chrono::Utc::now().to_rfc3339(),
+            uid: self.uid,
+            user: &self.user,
+            pid: self.pid,
+            trusted: self.trusted,
+            op: format!("{:?}", report.op),
+            paths: report.paths,
+            success: report.error.is_none(),
+            error: report.error.map(|v| v.to_string()),
+        };
+        if let Err(e) = self.log.write(&entry) {
+            error!(
+                "could not write to audit log {}: {}",
+                self.log.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditLog, Entry};
+
+    fn entry(paths: &[String]) -> Entry<'_> {
+        Entry {
+            time: "2020-11-20T12:00:00+00:00".to_string(),
+            uid: 1000,
+            user: "alice",
+            pid: Some(42),
+            trusted: false,
+            op: "WopAddToStore".to_string(),
+            paths,
+            success: true,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn write() {
+        let dir = std::env::temp_dir().join(format!("nix-test-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let log = AuditLog::new(&path, 0, 1);
+        let paths = vec!["/nix/store/ffffffffffffffffffffffffffffffff-foo".to_string()];
+        log.write(&entry(&paths)).unwrap();
+        log.write(&entry(&paths)).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            r#"{"time":"2020-11-20T12:00:00+00:00","uid":1000,"user":"alice","pid":42,"trusted":false,"op":"WopAddToStore","paths":["/nix/store/ffffffffffffffffffffffffffffffff-foo"],"success":true,"error":null}"#
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate() {
+        let dir =
+            std::env::temp_dir().join(format!("nix-test-audit-rotate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        // every line is larger than half the limit, so every write rotates
+        let log = AuditLog::new(&path, 250, 2);
+        for _ in 0..4 {
+            log.write(&entry(&[])).unwrap();
+        }
+
+        let lines = |p: &str| {
+            std::fs::read_to_string(dir.join(p))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(lines("audit.log"), 1);
+        assert_eq!(lines("audit.log.1"), 1);
+        assert_eq!(lines("audit.log.2"), 1);
+        assert!(!dir.join("audit.log.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,23 @@
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+use nix_daemon::error::CommandResult;
+use nix_daemon::nix_store::NixStore;
+
+fn main() {
+    // stdout carries the protocol, so log to stderr only
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    if let Err(e) = run() {
+        error!("{}", e);
+        std::process::exit(e.get_code());
+    }
+}
+
+#[tokio::main]
+async fn run() -> CommandResult<()> {
+    let nix_store = NixStore::new()?;
+    nix_store.run().await?;
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+/// Information about a client connected to the daemon
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub uid: u32,
+    pub user: String,
+    pub pid: Option<i32>,
+    pub trusted: bool,
+    pub started: std::time::SystemTime,
+    /// The op the client is currently running
+    pub op: Option<String>,
+}
+
+struct Entry {
+    /// Set when the client passed the user checks
+    info: Option<ConnectionInfo>,
+    handle: JoinHandle<()>,
+}
+
+/// Registry of all connections currently served by the daemon.
+/// Every connection runs in its own task, this keeps track of those tasks.
+#[derive(Clone)]
+pub struct Connections {
+    next_id: Arc<AtomicU64>,
+    active: Arc<Mutex<HashMap<u64, Entry>>>,
+
+    /// Notified every time a connection is closed
+    closed: Arc<Notify>,
+
+    shutdown: Arc<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        Self {
+            next_id: Arc::new(AtomicU64::new(0)),
+            active: Arc::new(Mutex::new(HashMap::new())),
+            closed: Arc::new(Notify::new()),
+            shutdown: Arc::new(shutdown),
+            shutdown_rx,
+        }
+    }
+
+    /// Reserve an id for a newly accepted connection
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Track the task serving the connection with the given id
+    pub fn insert(&self, id: u64, handle: JoinHandle<()>) {
+        let mut active = self.active.lock().unwrap();
+        active.insert(id, Entry { info: None, handle });
+    }
+
+    /// Attach the client information once the peer is known
+    pub fn set_info(&self, info: ConnectionInfo) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(entry) = active.get_mut(&info.id) {
+            entry.info = Some(info);
+        }
+    }
+
+    /// Set the op currently running on the connection
+    pub fn set_op(&self, id: u64, op: Option<String>) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(info) = active.get_mut(&id).and_then(|v| v.info.as_mut()) {
+            info.op = op;
+        }
+    }
+
+    pub fn remove(&self, id: u64) {
+        let mut active = self.active.lock().unwrap();
+        active.remove(&id);
+        drop(active);
+        self.closed.notify_waiters();
+    }
+
+    /// Returns a guard which removes the connection from the registry when dropped.
+    /// This also covers connections whose task got cancelled.
+    pub fn guard(&self, id: u64) -> ConnectionGuard {
+        ConnectionGuard {
+            id,
+            connections: self.clone(),
+        }
+    }
+
+    /// List all clients which are currently connected
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let active = self.active.lock().unwrap();
+        let mut list: Vec<ConnectionInfo> =
+            active.values().filter_map(|v| v.info.clone()).collect();
+        list.sort_by_key(|v| v.id);
+        list
+    }
+
+    /// Number of connections of the user with the given uid
+    pub fn count_uid(&self, uid: u32) -> usize {
+        let active = self.active.lock().unwrap();
+        active
+            .values()
+            .filter(|v| v.info.as_ref().map(|v| v.uid) == Some(uid))
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Receiver passed to every connection, turns true once the daemon shuts down
+    pub fn shutdown_receiver(&self) -> watch::Receiver<bool> {
+        self.shutdown_rx.clone()
+    }
+
+    /// Ask all connections to close after their current operation
+    pub fn request_shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Wait until all connections are closed.
+    /// Returns false if there are still connections open after `timeout`.
+    pub async fn drain(&self, timeout: std::time::Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.is_empty() {
+            if tokio::time::timeout_at(deadline, self.closed.notified())
+                .await
+                .is_err()
+            {
+                return self.is_empty();
+            }
+        }
+        true
+    }
+
+    /// Cancel all running connections and wait until their tasks are dropped
+    pub async fn cancel(&self) {
+        // the guards of the aborted tasks lock `active` again
+        let entries: Vec<(u64, Entry)> = self.active.lock().unwrap().drain().collect();
+        for (id, entry) in entries {
+            debug!("cancelling connection {}", id);
+            entry.handle.abort();
+            let _ = entry.handle.await;
+        }
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ConnectionGuard {
+    id: u64,
+    connections: Connections,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.remove(self.id);
+    }
+}
@@ -7,9 +7,10 @@ custom_error! {
         Io{source: io::Error} = "IoError: {source}",
         UtilParse{source: libutil::config::error::Error} = "parsing error: {source}",
         Tokio{source: tokio::task::JoinError} = "tokio error: {source}",
+        Store{source: libstore::StoreError} = "store error: {source}",
+        Worker{status: std::process::ExitStatus} = "worker failed with {status}",
         DisallowedUser{user: String} = "User {user} is not allwod to connect to the Nix daemon",
-        InvalidMagic{} = "the magic number is invalid",
-        InvalidVersion{} = "the client version is to old",
+        TooManyConnections{user: String, max: usize} = "user {user} already has the maximum of {max} connections to the Nix daemon",
 }
 
 impl CommandError {
@@ -18,10 +19,11 @@ impl CommandError {
             CommandError::Io { .. } => 2, // TODO: get from io::Error (source)
             CommandError::UtilParse { .. } => 3,
             CommandError::Tokio { .. } => 4,
+            CommandError::Store { .. } => 1,
+            CommandError::Worker { .. } => 1,
+            CommandError::TooManyConnections { .. } => 1,
 
             CommandError::DisallowedUser { .. } => 200,
-            CommandError::InvalidMagic {} => 200,
-            CommandError::InvalidVersion {} => 200,
         }
     }
 }
@@ -1,8 +1,9 @@
-use std::convert::TryInto;
+use std::rc::Rc;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::stream::StreamExt;
+use tokio::sync::watch;
 
 use libstore::connection::Connection;
 
@@ -10,12 +11,30 @@ use libstore::connection::Connection;
 extern crate log;
 use clap::{App, Arg};
 
+use connections::{ConnectionInfo, Connections};
 use error::CommandResult;
 
+pub mod admin;
+pub mod audit;
+pub mod connections;
 pub mod error;
+pub mod nix_store;
+pub mod peer;
+pub mod stats;
+pub mod systemd;
+pub mod worker;
 
 pub struct NixDaemon {
     pub stdio: bool,
+    /// Set if we are a worker process started by the daemon
+    pub worker: Option<worker::Client>,
+
+    config_file: std::path::PathBuf,
+    /// Settings from the command line, applied on top of the config file
+    overrides: Vec<(String, String)>,
+
+    connections: Connections,
+    stats: stats::Stats,
 }
 
 impl NixDaemon {
@@ -43,8 +62,47 @@ impl NixDaemon {
                     .help("set nix conifg file")
                     .takes_value(true)
                     .default_value("/etc/nix/nix.conf"),
+            )
+            .arg(
+                Arg::with_name("option")
+                    .long("option")
+                    .help("set the config option <name> to <value>, overriding nix.conf")
+                    .value_names(&["name", "value"])
+                    .number_of_values(2)
+                    .multiple(true),
+            )
+            .arg(
+                Arg::with_name("store")
+                    .long("store")
+                    .help("URI of the store to serve")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("worker")
+                    .long("worker")
+                    .help("serve a single client passed by the daemon")
+                    .takes_value(false)
+                    .requires_all(&["worker-uid", "worker-user"])
+                    .hidden(true),
+            )
+            .arg(
+                Arg::with_name("worker-uid")
+                    .long("worker-uid")
+                    .takes_value(true)
+                    .hidden(true),
+            )
+            .arg(
+                Arg::with_name("worker-user")
+                    .long("worker-user")
+                    .takes_value(true)
+                    .hidden(true),
+            )
+            .arg(
+                Arg::with_name("worker-trusted")
+                    .long("worker-trusted")
+                    .takes_value(false)
+                    .hidden(true),
             );
-        // FIXME: add all other options
 
         if cfg!(feature = "color") {
             app = app
@@ -54,14 +112,29 @@ impl NixDaemon {
 
         let matches = app.get_matches();
 
-        let config_file = std::path::Path::new(matches.value_of("config").unwrap());
-        let nix_config = libutil::config::NixConfig::parse_file(config_file)?;
-        let mut store_config = libstore::CONFIG.write().unwrap();
-        *store_config = nix_config;
-        drop(store_config);
-        // TODO: merge with args
+        let config_file = std::path::PathBuf::from(matches.value_of("config").unwrap());
 
-        let mut config = Self { stdio: false };
+        let mut overrides = Vec::new();
+        if let Some(values) = matches.values_of("option") {
+            let values: Vec<&str> = values.collect();
+            for v in values.chunks(2) {
+                overrides.push((v[0].to_string(), v[1].to_string()));
+            }
+        }
+        if let Some(store) = matches.value_of("store") {
+            overrides.push(("store".to_string(), store.to_string()));
+        }
+
+        load_config(&config_file, &overrides)?;
+
+        let mut config = Self {
+            stdio: false,
+            worker: None,
+            config_file,
+            overrides,
+            connections: Connections::new(),
+            stats: stats::Stats::new(),
+        };
 
         if matches.is_present("daemon") {
             info!("provided `--daemon` which is only here for backward compability");
@@ -72,48 +145,140 @@ impl NixDaemon {
             config.stdio = true;
         }
 
+        if matches.is_present("worker") {
+            let uid = matches.value_of("worker-uid").unwrap();
+            config.worker = Some(worker::Client {
+                uid: uid.parse().map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid uid '{}'", uid),
+                    )
+                })?,
+                user: matches.value_of("worker-user").unwrap().to_string(),
+                trusted: matches.is_present("worker-trusted"),
+            });
+        }
+
         Ok(config)
     }
 
-    #[allow(unused_must_use)]
     pub async fn run(self) -> CommandResult<()> {
-        if self.stdio {
-            // implement stdio for other store types
-            /*let stream = UnixStream::connect(&self.nix_config.nix_daemon_socket_file)?;
-
-            let socket_arc = std::sync::Arc::new(stream);
-            let (mut socket_tx, mut socket_rx) = (socket_arc.try_clone()?, socket_arc.try_clone()?);
-
-            use std::io::{copy, stdin, stdout};
-            use std::thread::spawn;
-            let connections = vec![ // This is broken. Rewrite to async?
-                spawn(move || loop { copy(&mut stdin(), &mut socket_tx); } ),
-                spawn(move || loop { copy(&mut socket_rx, &mut stdout()); } ),
-            ];
-
-            for t in connections {
-                t.join().unwrap();
-            }*/
-            /*let socket_file = &self.nix_config.nix_daemon_socket_file;
-            debug!("stdio: connecting to socket {}", socket_file);
-
-            let mut stream = UnixStream::connect(socket_file).await?;
-            let (mut read, mut write) = stream.split();
+        if let Some(client) = self.worker.clone() {
+            self.worker(client).await
+        } else if self.stdio {
+            self.stdio().await
+        } else {
+            self.daemon_loop().await
+        }
+    }
 
-            let mut stdin = io::stdin();
-            let mut stdout = io::stdout();
+    /// Serve the client the daemon passed to us on `worker::CLIENT_FD`
+    async fn worker(self, client: worker::Client) -> CommandResult<()> {
+        let (store, audit) = {
+            let config = libstore::CONFIG.read().unwrap();
+            (
+                config.store.to_string(),
+                audit::AuditLog::from_config(&config),
+            )
+        };
+        let stream = worker::client_stream()?;
+
+        // the daemon keeps the stats, it learns about our ops from the reports
+        let mut observers: Vec<Box<dyn libstore::connection::Observer>> =
+            vec![Box::new(worker::Reporter::new()?)];
+        if let Some(log) = audit {
+            observers.push(Box::new(audit::Observer {
+                log,
+                uid: client.uid,
+                user: client.user.clone(),
+                pid: stream.peer_cred()?.pid(),
+                trusted: client.trusted,
+            }));
+        }
 
-            warn!("copying");
+        // the daemon sends SIGTERM when it shuts down, SIGINT reaches us from a terminal
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let (shutdown, shutdown_rx) = watch::channel(false);
+
+        let local = tokio::task::LocalSet::new();
+        let served = local
+            .run_until(async {
+                tokio::task::spawn_local(async move {
+                    tokio::select! {
+                        _ = terminate.recv() => {},
+                        _ = interrupt.recv() => {},
+                    }
+                    let _ = shutdown.send(true);
+                });
+                serve_connection(
+                    stream,
+                    &store,
+                    client.uid,
+                    client.user,
+                    client.trusted,
+                    Some(shutdown_rx),
+                    observers,
+                )
+                .await
+            })
+            .await;
+
+        served
+    }
 
-            //let join = futures::future::join_all(connections).await;
-            let join = futures::future::join(io::copy(&mut stdin, &mut write), io::copy(&mut read, &mut stdout)).await;
+    /// Forward stdin/stdout to the running daemon.
+    /// If no daemon is running serve the worker protocol on stdin/stdout ourself.
+    async fn stdio(self) -> CommandResult<()> {
+        let (socket_file, store) = {
+            let config = libstore::CONFIG.read().unwrap();
+            (
+                config.nix_daemon_socket_file.to_string(),
+                config.store.to_string(),
+            )
+        };
 
-            warn!("join: ${:?}", join);*/
-            unimplemented!();
-        } else {
-            self.daemon_loop().await?;
+        match std::os::unix::net::UnixStream::connect(&socket_file) {
+            Ok(stream) => {
+                debug!("stdio: connected to socket {}", socket_file);
+                tokio::task::spawn_blocking(move || proxy_stdio(stream)).await??;
+            }
+            Err(e) => {
+                debug!(
+                    "stdio: could not connect to {} ({}), serving on stdio",
+                    socket_file, e
+                );
+                let (client, server) = std::os::unix::net::UnixStream::pair()?;
+                server.set_nonblocking(true)?;
+                let server = UnixStream::from_std(server)?;
+
+                let proxy = tokio::task::spawn_blocking(move || proxy_stdio(client));
+
+                let uid = unsafe { libc::getuid() };
+                let user = match users::get_user_by_uid(uid) {
+                    Some(v) => v.name().to_string_lossy().to_string(),
+                    None => uid.to_string(),
+                };
+
+                // the user could access the store without us, so trust them
+                let local = tokio::task::LocalSet::new();
+                let served = local
+                    .run_until(serve_connection(
+                        server,
+                        &store,
+                        uid,
+                        user,
+                        true,
+                        None,
+                        Vec::new(),
+                    ))
+                    .await;
+                proxy.await??;
+                served?;
+            }
         }
-        Ok(()) // FIXME: unreachable() //?
+
+        Ok(())
     }
 
     async fn daemon_loop(self) -> CommandResult<()> {
@@ -121,131 +286,450 @@ impl NixDaemon {
 
         // TODO: get rid of zombies
 
-        #[allow(unused_assignments)]
-        let mut listener: Option<UnixListener> = None;
-
-        if let Ok(listen_fds) = std::env::var("LISTEN_FDS") {
-            let fd: i32 = listen_fds.parse().unwrap();
-            let raw_fd: std::os::unix::net::UnixListener =
-                unsafe { std::os::unix::io::FromRawFd::from_raw_fd(fd) };
-            listener = UnixListener::from_std(raw_fd).ok();
+        let mut listeners: Vec<UnixListener> = Vec::new();
+        let mut admin_listener: Option<UnixListener> = None;
+        // only set if we created the socket, sockets from systemd are not ours to remove
+        let mut socket_file: Option<String> = None;
+        let mut admin_socket_file: Option<String> = None;
+
+        let listen_fds = systemd::listen_fds(true)?;
+        if !listen_fds.is_empty() {
+            for v in listen_fds {
+                info!("listening on systemd socket '{}' (fd {})", v.name, v.fd);
+                let listener: std::os::unix::net::UnixListener =
+                    unsafe { std::os::unix::io::FromRawFd::from_raw_fd(v.fd) };
+                listener.set_nonblocking(true)?;
+                let listener = UnixListener::from_std(listener)?;
+                if v.name == "admin" {
+                    admin_listener = Some(listener);
+                } else {
+                    listeners.push(listener);
+                }
+            }
         } else {
             let config = libstore::CONFIG.read().unwrap();
             let file = config.nix_daemon_socket_file.to_string();
             drop(config);
             info!("listening on {}", file);
             // TODO: create dirs
-            listener = Some(UnixListener::bind(&file)?);
+            listeners.push(UnixListener::bind(&file)?);
 
             // set permissions
             use std::os::unix::fs::PermissionsExt;
             let perms = std::fs::Permissions::from_mode(0o666);
             std::fs::set_permissions(&file, perms)?;
 
-            // TODO: exit trap to remove socket
+            socket_file = Some(file);
+
+            let file = libstore::CONFIG
+                .read()
+                .unwrap()
+                .daemon_admin_socket_file
+                .to_string();
+            if !file.is_empty() {
+                info!("listening for admin clients on {}", file);
+                // created without access for others, there is no window before the chmod
+                let umask = unsafe { libc::umask(0o077) };
+                let admin = UnixListener::bind(&file);
+                unsafe { libc::umask(umask) };
+                admin_listener = Some(admin?);
+                let perms = std::fs::Permissions::from_mode(0o600);
+                std::fs::set_permissions(&file, perms)?;
+                admin_socket_file = Some(file);
+            }
         }
 
-        let mut listener = listener.expect("there is no listener");
-
-        while let Some(stream) = listener.next().await {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.handle_connection(stream).await {
-                        // FIXME: multithreading!!!
-                        // TODO: print errors
-                        warn!("{}", e);
+        let mut listener = futures::stream::select_all(listeners);
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        // the store futures are not `Send`, so all connections are served on this thread
+        let local = tokio::task::LocalSet::new();
+        let daemon = Rc::new(self);
+        local
+            .run_until(async move {
+                let admin = admin_listener.map(|listener| {
+                    tokio::task::spawn_local(admin::serve(
+                        listener,
+                        daemon.connections.clone(),
+                        daemon.stats.clone(),
+                    ))
+                });
+
+                systemd::notify_or_warn("READY=1\nSTATUS=accepting connections");
+                loop {
+                    tokio::select! {
+                        stream = listener.next() => match stream {
+                            Some(Ok(stream)) => daemon.spawn_connection(stream),
+                            Some(Err(e)) => {
+                                warn!("Error accepting connection: {}", e);
+                            }
+                            None => break,
+                        },
+                        _ = terminate.recv() => {
+                            info!("received SIGTERM, shutting down");
+                            break;
+                        }
+                        _ = interrupt.recv() => {
+                            info!("received SIGINT, shutting down");
+                            break;
+                        }
+                        _ = hangup.recv() => {
+                            info!("received SIGHUP, reloading {}", daemon.config_file.display());
+                            systemd::notify_or_warn("RELOADING=1");
+                            if let Err(e) = daemon.reload_config() {
+                                error!("could not reload config, keeping the old one: {}", e);
+                            }
+                            systemd::notify_or_warn("READY=1\nSTATUS=accepting connections");
+                        }
                     }
                 }
-                Err(e) => {
-                    warn!("Error accepting connection: {}", e);
+                // stop accepting new connections
+                drop(listener);
+                if let Some(admin) = admin {
+                    admin.abort();
                 }
-            }
+
+                daemon.shutdown().await;
+            })
+            .await;
+
+        for file in socket_file.iter().chain(admin_socket_file.iter()) {
+            debug!("removing socket {}", file);
+            std::fs::remove_file(file)?;
         }
 
         Ok(())
     }
 
-    async fn handle_connection(&self, stream: UnixStream) -> CommandResult<()> {
-        let mut stream = stream;
+    /// Parse the config file again and replace `libstore::CONFIG`.
+    /// Running connections keep the settings they already read, new connections use the new ones.
+    fn reload_config(&self) -> CommandResult<()> {
+        let mut config = libutil::config::NixConfig::parse_file_with_overrides(
+            &self.config_file,
+            &self.overrides,
+        )?;
+        config.validate()?;
+
+        let mut store_config = libstore::CONFIG.write().unwrap();
+        let ignored = keep_static_settings(&store_config, &mut config);
+        *store_config = config;
+        drop(store_config);
+
+        if !ignored.is_empty() {
+            warn!(
+                "ignoring the new values of {}, a restart is required to change them",
+                ignored.join(", ")
+            );
+        }
+        info!("reloaded {}", self.config_file.display());
+        Ok(())
+    }
 
+    /// Let running operations finish, cancel everything still running after
+    /// `daemon-shutdown-timeout` and release the resources held by the daemon.
+    async fn shutdown(&self) {
+        let timeout = libstore::CONFIG.read().unwrap().daemon_shutdown_timeout;
+
+        self.connections.request_shutdown();
+        systemd::notify_or_warn(&format!(
+            "STOPPING=1\nSTATUS=waiting for {} connections to finish",
+            self.connections.len()
+        ));
+        if !self.connections.is_empty() {
+            info!(
+                "waiting up to {}s for {} connections to finish",
+                timeout,
+                self.connections.len()
+            );
+        }
+        if !self
+            .connections
+            .drain(std::time::Duration::from_secs(timeout as u64))
+            .await
+        {
+            warn!(
+                "cancelling {} connections still running after {}s",
+                self.connections.len(),
+                timeout
+            );
+            systemd::notify_or_warn("STATUS=cancelling running connections");
+            // dropping the tasks also releases the build users they hold
+            self.connections.cancel().await;
+        }
+    }
+
+    /// Connections which are currently served by the daemon
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    fn spawn_connection(self: &Rc<Self>, stream: UnixStream) {
+        let id = self.connections.next_id();
+        let daemon = self.clone();
+
+        let handle = tokio::task::spawn_local(async move {
+            let _guard = daemon.connections.guard(id);
+            let shutdown = daemon.connections.shutdown_receiver();
+            if let Err(e) = daemon.handle_connection(id, stream, shutdown).await {
+                warn!("connection {}: {}", id, e);
+            }
+            trace!("connection {} closed", id);
+        });
+        self.connections.insert(id, handle);
+        debug!("{} active connections", self.connections.len());
+    }
+
+    async fn handle_connection(
+        &self,
+        id: u64,
+        stream: UnixStream,
+        shutdown: watch::Receiver<bool>,
+    ) -> CommandResult<()> {
         let creds = stream.peer_cred()?;
 
-        //let user = users::get_user_by_uid(creds.uid);
-        let user = match users::get_user_by_uid(creds.uid()) {
-            Some(v) => v.name().to_string_lossy().to_string(),
-            None => "not allowed user".to_string(),
-        };
-        let group = match users::get_group_by_gid(creds.gid()) {
-            Some(v) => v.name().to_string_lossy().to_string(),
-            None => "not allowed group".to_string(),
-        };
+        let peer::Peer { user, groups } =
+            peer::resolve(&peer::SystemUserDb, creds.uid(), creds.gid());
+        trace!("user {} has groups {:?}", user, groups);
 
         let config = libstore::CONFIG.read().unwrap();
-        let trusted = config.is_trusted_user(&user, &group);
+        let trusted = config.is_trusted_user(&user, &groups);
 
-        if !config.is_allowed_user(&user, &group) {
+        if !config.is_allowed_user(&user, &groups) {
             return Err(crate::error::CommandError::DisallowedUser { user });
         }
         let store = config.store.to_string();
+        let worker_processes = config.daemon_worker_processes;
+        let audit = audit::AuditLog::from_config(&config);
+        let max_connections = config.daemon_max_connections_per_user;
         drop(config);
 
+        if max_connections != 0 && self.connections.count_uid(creds.uid()) >= max_connections {
+            let e = crate::error::CommandError::TooManyConnections {
+                user,
+                max: max_connections,
+            };
+            reject_connection(stream, &e.to_string()).await?;
+            return Err(e);
+        }
+
         info!(
             "accepted connection from user {}{}{}",
             user,
             if trusted { " (trusted)" } else { "" },
-            if let Some(pid) = creds.pid() { format!(" pid: {}", pid) } else { "".to_string() }
+            if let Some(pid) = creds.pid() {
+                format!(" pid: {}", pid)
+            } else {
+                "".to_string()
+            }
         ); // TODO: pid
 
-        // verify client version
-        let mut buffer: [u8; 10] = [0; 10];
-
-        stream.read(&mut buffer[..]).await?;
+        self.connections.set_info(ConnectionInfo {
+            id,
+            uid: creds.uid(),
+            user: user.clone(),
+            pid: creds.pid(),
+            trusted,
+            started: std::time::SystemTime::now(),
+            op: None,
+        });
+
+        let observer = stats::Observer {
+            id,
+            connections: self.connections.clone(),
+            stats: self.stats.clone(),
+        };
+        if worker_processes {
+            let client = worker::Client {
+                uid: creds.uid(),
+                user,
+                trusted,
+            };
+            return worker::spawn(
+                stream,
+                &client,
+                &self.config_file,
+                &self.overrides,
+                &store,
+                shutdown,
+                &observer,
+            )
+            .await;
+        }
 
-        let magic = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
-        if magic != libstore::connection::WORKER_MAGIC_1 {
-            return Err(crate::error::CommandError::InvalidMagic {});
+        let mut observers: Vec<Box<dyn libstore::connection::Observer>> = vec![Box::new(observer)];
+        if let Some(log) = audit {
+            observers.push(Box::new(audit::Observer {
+                log,
+                uid: creds.uid(),
+                user: user.clone(),
+                pid: creds.pid(),
+                trusted,
+            }));
         }
+        serve_connection(
+            stream,
+            &store,
+            creds.uid(),
+            user,
+            trusted,
+            Some(shutdown),
+            observers,
+        )
+        .await
+    }
+}
 
-        let magic = u32::to_le_bytes(libstore::connection::WORKER_MAGIC_2);
-        let version = u16::to_le_bytes(libstore::connection::PROTOCOL_VERSION);
-        stream.write(&magic).await?;
-        stream.write(&[0, 0, 0, 0]).await?;
-        stream.write(&version).await?;
-        stream.write(&[0, 0, 0, 0, 0, 0]).await?;
+/// Parse the config file, apply `overrides` and make it the config of libstore
+pub fn load_config(
+    config_file: &std::path::Path,
+    overrides: &[(String, String)],
+) -> CommandResult<()> {
+    let nix_config = libutil::config::NixConfig::parse_file_with_overrides(config_file, overrides)?;
+    nix_config.validate()?;
+    let mut store_config = libstore::CONFIG.write().unwrap();
+    *store_config = nix_config;
+    Ok(())
+}
 
-        stream.read(&mut buffer[..]).await?;
-        let version = u16::from_le_bytes(buffer[0..2].try_into().unwrap());
-        if version < 0x10a {
-            return Err(crate::error::CommandError::InvalidVersion {});
-        }
+/// Tell a client why it is not served, the error replaces the end of the handshake
+async fn reject_connection(stream: UnixStream, msg: &str) -> CommandResult<()> {
+    use libstore::source::Logger;
 
-        stream.read(&mut buffer[..]).await?;
-        stream.read(&mut buffer[..]).await?;
+    let con = libstore::source::Connection::new(stream);
+    libstore::connection::handshake(&con).await?;
+    con.stop_work(libstore::source::WorkFinish::Error(msg.to_string(), 1))
+        .await?;
+    Ok(())
+}
 
-        trace!("version an client matching");
+/// Run the worker protocol for an already accepted client
+async fn serve_connection(
+    stream: UnixStream,
+    store: &str,
+    uid: u32,
+    user: String,
+    trusted: bool,
+    shutdown: Option<watch::Receiver<bool>>,
+    observers: Vec<Box<dyn libstore::connection::Observer>>,
+) -> CommandResult<()> {
+    let con = libstore::source::Connection::new(stream);
+    let version = libstore::connection::handshake(&con).await?;
+
+    let params = std::collections::HashMap::new();
+    // TODO: override settings via Params
+
+    let store = libstore::open_store(store, params).await?;
+
+    let mut connection = Connection::new(trusted, version, con, store, uid, user);
+    if let Some(shutdown) = shutdown {
+        connection.set_shutdown(shutdown);
+    }
+    for observer in observers {
+        connection.add_observer(observer);
+    }
 
-        let params = std::collections::HashMap::new();
-        // TODO: override settings via Params
+    #[allow(clippy::single_match)] // TODO: add magic?
+    match connection.run().await {
+        // TODO:
+        // FIXME: error
+        Err(e) => {
+            trace!("shutting down stream");
+            info!("got error {} from daemon loop", e);
+            //stream.shutdown(std::net::Shutdown::Both)?; // TODO: where to shutndown
+            //Err(e);
+        }
+        Ok(_) => {}
+    }
 
-        let store = libstore::open_store(&store, params).await.unwrap();
+    Ok(())
+}
 
-        let con = libstore::source::Connection::new(stream);
+/// Keep the settings of `old` which can not change while the daemon is running.
+/// Returns the names of the settings which were changed in `new`.
+fn keep_static_settings(
+    old: &libutil::config::NixConfig,
+    new: &mut libutil::config::NixConfig,
+) -> Vec<&'static str> {
+    let mut ignored = Vec::new();
+    let mut keep = |name: &'static str, old: &String, new: &mut String| {
+        if old != new {
+            debug!("keeping {} = '{}' instead of '{}'", name, old, new);
+            *new = old.clone();
+            ignored.push(name);
+        }
+    };
+
+    keep("store", &old.store, &mut new.store);
+    keep("nix-state-dir", &old.nix_state_dir, &mut new.nix_state_dir);
+    keep(
+        "nix-daemon-socket-file",
+        &old.nix_daemon_socket_file,
+        &mut new.nix_daemon_socket_file,
+    );
+    keep(
+        "daemon-admin-socket-file",
+        &old.daemon_admin_socket_file,
+        &mut new.daemon_admin_socket_file,
+    );
+
+    ignored
+}
 
-        let connection = Connection::new(trusted, version, con, store, creds.uid(), user);
+/// Copy stdin to `stream` and `stream` to stdout until the other side closes the stream
+fn proxy_stdio(stream: std::os::unix::net::UnixStream) -> std::io::Result<()> {
+    use std::io::{Read, Write};
 
-        #[allow(clippy::single_match)] // TODO: add magic?
-        match connection.run().await {
-            // TODO:
-            // FIXME: error
-            Err(e) => {
-                trace!("shutting down stream");
-                info!("got error {} from daemon loop", e);
-                //stream.shutdown(std::net::Shutdown::Both)?; // TODO: where to shutndown
-                //Err(e);
-            }
-            Ok(_) => {}
+    let mut to_daemon = stream.try_clone()?;
+    // this thread is not joined, reading stdin blocks until the client closes it
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        if let Err(e) = std::io::copy(&mut stdin.lock(), &mut to_daemon) {
+            warn!("stdio: could not forward stdin: {}", e);
+        }
+        let _ = to_daemon.shutdown(std::net::Shutdown::Write);
+    });
+
+    let mut from_daemon = stream;
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let len = from_daemon.read(&mut buf)?;
+        if len == 0 {
+            break;
         }
+        stdout.write_all(&buf[..len])?;
+        stdout.flush()?;
+    }
 
-        Ok(())
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use libutil::config::NixConfig;
+
+    #[test]
+    fn keep_static_settings() {
+        let old = NixConfig {
+            store: "/nix/store".to_string(),
+            nix_daemon_socket_file: "/nix/var/nix/daemon-socket/socket".to_string(),
+            max_jobs: "1".to_string(),
+            ..Default::default()
+        };
+        let mut new = NixConfig {
+            store: "/nix/store".to_string(),
+            nix_daemon_socket_file: "/tmp/socket".to_string(),
+            max_jobs: "8".to_string(),
+            ..Default::default()
+        };
+
+        let ignored = super::keep_static_settings(&old, &mut new);
+        assert_eq!(ignored, vec!["nix-daemon-socket-file"]);
+        assert_eq!(new.nix_daemon_socket_file, old.nix_daemon_socket_file);
+        assert_eq!(new.max_jobs, "8");
     }
 }
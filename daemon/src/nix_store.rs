@@ -0,0 +1,93 @@
+//! `nix-store` command, only `--serve` is implemented.
+//! This lets rusty-nix act as a remote build machine for Hydra and `nix-copy-closure`.
+
+use clap::{App, Arg};
+
+use crate::error::CommandResult;
+
+pub struct NixStore {
+    /// Allow the client to import and build paths
+    pub write: bool,
+}
+
+impl NixStore {
+    pub fn new() -> CommandResult<Self> {
+        let mut app = App::new("nix-store")
+            .version(env!("CARGO_PKG_VERSION"))
+            .author(env!("CARGO_PKG_AUTHORS"))
+            .about("manipulate or query the Nix store")
+            .arg(
+                Arg::with_name("serve")
+                    .long("serve")
+                    .help("serve the store on stdin/stdout, used by remote builders")
+                    .takes_value(false)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("write")
+                    .long("write")
+                    .help("allow the client to import and build paths")
+                    .takes_value(false)
+                    .requires("serve"),
+            )
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .short("c")
+                    .help("set nix conifg file")
+                    .takes_value(true)
+                    .default_value("/etc/nix/nix.conf"),
+            )
+            .arg(
+                Arg::with_name("option")
+                    .long("option")
+                    .help("set the config option <name> to <value>, overriding nix.conf")
+                    .value_names(&["name", "value"])
+                    .number_of_values(2)
+                    .multiple(true),
+            )
+            .arg(
+                Arg::with_name("store")
+                    .long("store")
+                    .help("URI of the store to serve")
+                    .takes_value(true),
+            );
+
+        if cfg!(feature = "color") {
+            app = app
+                .setting(clap::AppSettings::ColorAuto)
+                .setting(clap::AppSettings::ColoredHelp);
+        }
+
+        let matches = app.get_matches();
+
+        let config_file = std::path::PathBuf::from(matches.value_of("config").unwrap());
+        let mut overrides = Vec::new();
+        if let Some(values) = matches.values_of("option") {
+            let values: Vec<&str> = values.collect();
+            for v in values.chunks(2) {
+                overrides.push((v[0].to_string(), v[1].to_string()));
+            }
+        }
+        if let Some(store) = matches.value_of("store") {
+            overrides.push(("store".to_string(), store.to_string()));
+        }
+        crate::load_config(&config_file, &overrides)?;
+
+        Ok(Self {
+            write: matches.is_present("write"),
+        })
+    }
+
+    /// Serve the store on stdin/stdout until the client disconnects
+    pub async fn run(self) -> CommandResult<()> {
+        let store = libstore::CONFIG.read().unwrap().store.to_string();
+        let store = libstore::open_store(&store, std::collections::HashMap::new()).await?;
+
+        let con = libstore::source::Stdio::new();
+        let connection = libstore::connection::serve::ServeConnection::new(con, store, self.write);
+        connection.run().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! User and groups of a connecting client
+
+use std::collections::HashSet;
+
+/// User name and all group names of a client, used to match `trusted-users` and `allowed-users`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub user: String,
+    /// Primary and supplementary groups
+    pub groups: HashSet<String>,
+}
+
+/// Source of user and group information, the system database or a fake one in tests
+pub trait UserDb {
+    fn user_name(&self, uid: u32) -> Option<String>;
+
+    fn group_name(&self, gid: u32) -> Option<String>;
+
+    /// Names of all groups of `user`, including `gid`.
+    /// Returns `None` if the user is unknown.
+    fn user_groups(&self, user: &str, gid: u32) -> Option<Vec<String>>;
+}
+
+/// The passwd and group database of the system, see getgrouplist(3)
+pub struct SystemUserDb;
+
+impl UserDb for SystemUserDb {
+    fn user_name(&self, uid: u32) -> Option<String> {
+        users::get_user_by_uid(uid).map(|v| v.name().to_string_lossy().to_string())
+    }
+
+    fn group_name(&self, gid: u32) -> Option<String> {
+        users::get_group_by_gid(gid).map(|v| v.name().to_string_lossy().to_string())
+    }
+
+    fn user_groups(&self, user: &str, gid: u32) -> Option<Vec<String>> {
+        users::get_user_groups(user, gid).map(|v| {
+            v.iter()
+                .map(|v| v.name().to_string_lossy().to_string())
+                .collect()
+        })
+    }
+}
+
+/// Resolve the user `uid` connecting with the primary group `gid`
+pub fn resolve<D: UserDb>(db: &D, uid: u32, gid: u32) -> Peer {
+    let user = match db.user_name(uid) {
+        Some(v) => v,
+        None => {
+            return Peer {
+                user: "not allowed user".to_string(),
+                groups: db.group_name(gid).into_iter().collect(),
+            }
+        }
+    };
+
+    let mut groups: HashSet<String> = db
+        .user_groups(&user, gid)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    // the peer may run with a group the database does not list for the user
+    if let Some(v) = db.group_name(gid) {
+        groups.insert(v);
+    }
+
+    Peer { user, groups }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve, UserDb};
+    use std::collections::HashSet;
+
+    /// Database in the format of /etc/passwd and /etc/group
+    struct FakeUserDb {
+        passwd: &'static str,
+        group: &'static str,
+    }
+
+    impl FakeUserDb {
+        fn passwd(&self) -> impl Iterator<Item = Vec<&'static str>> {
+            self.passwd.lines().map(|v| v.split(':').collect())
+        }
+
+        fn group(&self) -> impl Iterator<Item = Vec<&'static str>> {
+            self.group.lines().map(|v| v.split(':').collect())
+        }
+    }
+
+    impl UserDb for FakeUserDb {
+        fn user_name(&self, uid: u32) -> Option<String> {
+            self.passwd()
+                .find(|v| v[2] == uid.to_string())
+                .map(|v| v[0].to_string())
+        }
+
+        fn group_name(&self, gid: u32) -> Option<String> {
+            self.group()
+                .find(|v| v[2] == gid.to_string())
+                .map(|v| v[0].to_string())
+        }
+
+        fn user_groups(&self, user: &str, gid: u32) -> Option<Vec<String>> {
+            self.passwd().find(|v| v[0] == user)?;
+            Some(
+                self.group()
+                    .filter(|v| v[2] == gid.to_string() || v[3].split(',').any(|v| v == user))
+                    .map(|v| v[0].to_string())
+                    .collect(),
+            )
+        }
+    }
+
+    const DB: FakeUserDb = FakeUserDb {
+        passwd: "root:x:0:0::/root:/bin/sh\n\
+                 alice:x:1000:100::/home/alice:/bin/sh\n\
+                 bob:x:1001:100::/home/bob:/bin/sh",
+        group: "root:x:0:\n\
+                users:x:100:\n\
+                wheel:x:10:alice\n\
+                ci:x:200:bob,alice",
+    };
+
+    fn groups(v: &[&str]) -> HashSet<String> {
+        v.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn supplementary_groups() {
+        let alice = resolve(&DB, 1000, 100);
+        assert_eq!(alice.user, "alice");
+        assert_eq!(alice.groups, groups(&["users", "wheel", "ci"]));
+
+        let bob = resolve(&DB, 1001, 100);
+        assert_eq!(bob.groups, groups(&["users", "ci"]));
+    }
+
+    #[test]
+    fn peer_gid() {
+        // the client runs with a group it is not a member of, e.g. after newgrp
+        let bob = resolve(&DB, 1001, 10);
+        assert_eq!(bob.groups, groups(&["wheel", "ci"]));
+    }
+
+    #[test]
+    fn unknown_user() {
+        let peer = resolve(&DB, 4242, 100);
+        assert_eq!(peer.user, "not allowed user");
+        assert_eq!(peer.groups, groups(&["users"]));
+    }
+
+    #[test]
+    fn trusted_group() {
+        let config = libutil::config::NixConfig {
+            trusted_users: vec!["root".to_string(), "@wheel".to_string()],
+            ..Default::default()
+        };
+
+        // wheel is only a supplementary group of alice
+        let alice = resolve(&DB, 1000, 100);
+        assert!(config.is_trusted_user(&alice.user, &alice.groups));
+
+        let bob = resolve(&DB, 1001, 100);
+        assert!(!config.is_trusted_user(&bob.user, &bob.groups));
+    }
+}
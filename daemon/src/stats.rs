@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libstore::store::protocol::WorkerOp;
+use serde::Serialize;
+
+use crate::connections::Connections;
+
+/// Upper bounds of the latency histogram buckets in seconds
+pub const LATENCY_BUCKETS: [f64; 6] = [0.001, 0.01, 0.1, 1.0, 10.0, 60.0];
+
+/// Counters of a single op
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OpStats {
+    pub count: u64,
+    pub errors: u64,
+    /// Sum of the latencies in seconds
+    pub sum: f64,
+    /// Number of ops per bucket of `LATENCY_BUCKETS`, the last one counts everything above
+    pub buckets: Vec<u64>,
+}
+
+impl OpStats {
+    fn record(&mut self, duration: Duration, success: bool) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+        }
+
+        let secs = duration.as_secs_f64();
+        self.count += 1;
+        if !success {
+            self.errors += 1;
+        }
+        self.sum += secs;
+
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|v| secs <= *v)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+/// Per op counters of all connections
+#[derive(Clone, Default)]
+pub struct Stats {
+    ops: Arc<Mutex<BTreeMap<String, OpStats>>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, op: WorkerOp, duration: Duration, success: bool) {
+        let mut ops = self.ops.lock().unwrap();
+        ops.entry(format!("{:?}", op))
+            .or_default()
+            .record(duration, success);
+    }
+
+    pub fn ops(&self) -> BTreeMap<String, OpStats> {
+        self.ops.lock().unwrap().clone()
+    }
+}
+
+/// Tracks the ops of a single connection in the registry and the daemon wide stats
+pub struct Observer {
+    pub id: u64,
+    pub connections: Connections,
+    pub stats: Stats,
+}
+
+impl libstore::connection::Observer for Observer {
+    fn op_started(&self, op: WorkerOp) {
+        self.connections.set_op(self.id, Some(format!("{:?}", op)));
+    }
+
+    fn op_finished(&self, report: &libstore::connection::OpReport) {
+        self.finished(report.op, report.duration, report.error.is_none());
+    }
+}
+
+impl Observer {
+    /// Record an op which finished, also used for the ops reported by worker processes
+    pub fn finished(&self, op: WorkerOp, duration: Duration, success: bool) {
+        self.connections.set_op(self.id, None);
+        self.stats.record(op, duration, success);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Stats;
+    use libstore::store::protocol::WorkerOp;
+    use std::time::Duration;
+
+    #[test]
+    fn record() {
+        let stats = Stats::new();
+        stats.record(WorkerOp::WopIsValidPath, Duration::from_micros(500), true);
+        stats.record(WorkerOp::WopIsValidPath, Duration::from_millis(50), false);
+        stats.record(WorkerOp::WopBuildPaths, Duration::from_secs(120), true);
+
+        let ops = stats.ops();
+        let valid = &ops["WopIsValidPath"];
+        assert_eq!(valid.count, 2);
+        assert_eq!(valid.errors, 1);
+        assert_eq!(valid.buckets, vec![1, 0, 1, 0, 0, 0, 0]);
+
+        let build = &ops["WopBuildPaths"];
+        assert_eq!(build.count, 1);
+        assert_eq!(build.buckets, vec![0, 0, 0, 0, 0, 0, 1]);
+    }
+}
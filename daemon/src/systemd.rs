@@ -0,0 +1,225 @@
+//! systemd socket activation and readiness notification
+//! see sd_listen_fds(3) and sd_notify(3)
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// First file descriptor passed by systemd
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed to us by systemd
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListenFd {
+    pub fd: RawFd,
+    /// Name set via `FileDescriptorName=`, systemd uses "unknown" if not set
+    pub name: String,
+}
+
+/// Returns the sockets systemd passed to this process.
+/// The environment variables are removed if `unset_env` is set, so child processes do not inherit them.
+pub fn listen_fds(unset_env: bool) -> io::Result<Vec<ListenFd>> {
+    use std::env::var;
+
+    let fds = parse_listen_fds(
+        var("LISTEN_PID").ok().as_deref(),
+        var("LISTEN_FDS").ok().as_deref(),
+        var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    );
+
+    if unset_env {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let fds = fds?;
+    for v in &fds {
+        if unsafe { libc::fcntl(v.fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(fds)
+}
+
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> io::Result<Vec<ListenFd>> {
+    let listen_pid = match listen_pid {
+        Some(v) => v,
+        None => return Ok(Vec::new()),
+    };
+    let listen_pid: u32 = listen_pid
+        .parse()
+        .map_err(|_| invalid_data(format!("invalid LISTEN_PID: '{}'", listen_pid)))?;
+    if listen_pid != pid {
+        debug!("LISTEN_PID {} is not for us", listen_pid);
+        return Ok(Vec::new());
+    }
+
+    let count = match listen_fds {
+        Some(v) => v,
+        None => return Ok(Vec::new()),
+    };
+    let count: RawFd = count
+        .parse()
+        .map_err(|_| invalid_data(format!("invalid LISTEN_FDS: '{}'", count)))?;
+
+    let names: Vec<&str> = match listen_fdnames {
+        Some(v) => v.split(':').collect(),
+        None => Vec::new(),
+    };
+    if !names.is_empty() && names.len() != count as usize {
+        return Err(invalid_data(format!(
+            "LISTEN_FDNAMES has {} names for {} sockets",
+            names.len(),
+            count
+        )));
+    }
+
+    Ok((0..count)
+        .map(|i| ListenFd {
+            fd: SD_LISTEN_FDS_START + i,
+            name: names.get(i as usize).unwrap_or(&"unknown").to_string(),
+        })
+        .collect())
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Send `state` to the socket in `NOTIFY_SOCKET`.
+/// Returns false if we are not running under a `Type=notify` unit.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) => {
+            notify_socket(&socket, state)?;
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+/// Send `state` to the notification socket at `socket`.
+/// A leading '@' refers to the abstract socket namespace.
+pub fn notify_socket(socket: &str, state: &str) -> io::Result<()> {
+    trace!("sd_notify: {}", state.replace('\n', " "));
+
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let path = socket.as_bytes();
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(invalid_data(format!("invalid NOTIFY_SOCKET: '{}'", socket)));
+    }
+    for (i, v) in path.iter().enumerate() {
+        addr.sun_path[i] = *v as libc::c_char;
+    }
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let addr_len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let ret = unsafe {
+        libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        )
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+
+    if ret == -1 {
+        return Err(err);
+    }
+    if ret as usize != state.len() {
+        return Err(io::Error::from_raw_os_error(libc::EIO));
+    }
+
+    Ok(())
+}
+
+/// Notify systemd, errors are only logged as the daemon works without it
+pub fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("could not notify systemd: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_listen_fds, ListenFd};
+
+    #[test]
+    fn listen_fds() {
+        let fds = parse_listen_fds(Some("42"), Some("2"), None, 42).unwrap();
+        assert_eq!(
+            fds,
+            vec![
+                ListenFd {
+                    fd: 3,
+                    name: "unknown".to_string()
+                },
+                ListenFd {
+                    fd: 4,
+                    name: "unknown".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn listen_fds_names() {
+        let fds = parse_listen_fds(Some("42"), Some("2"), Some("daemon:admin"), 42).unwrap();
+        assert_eq!(fds[0].name, "daemon");
+        assert_eq!(fds[1].fd, 4);
+        assert_eq!(fds[1].name, "admin");
+
+        assert!(parse_listen_fds(Some("42"), Some("2"), Some("daemon"), 42).is_err());
+    }
+
+    #[test]
+    fn listen_fds_other_pid() {
+        assert!(parse_listen_fds(Some("41"), Some("1"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(None, Some("1"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(Some("foo"), Some("1"), None, 42).is_err());
+    }
+
+    #[test]
+    fn notify_socket() {
+        let path = format!("/tmp/nix-test-notify-{}", std::process::id());
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        super::notify_socket(&path, "READY=1\nSTATUS=accepting connections").unwrap();
+
+        let mut buf = [0; 128];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=accepting connections");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn notify_missing_socket() {
+        assert!(super::notify_socket("/tmp/nix-test-notify-missing", "READY=1").is_err());
+    }
+}
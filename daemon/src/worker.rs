@@ -0,0 +1,255 @@
+//! Worker processes serving a single client.
+//! The daemon re-executes itself with `--worker` and passes the client socket on `CLIENT_FD`,
+//! so a crash in one session does not take down the daemon or other sessions.
+//! Workers report their ops on `REPORT_FD`, so they show up in the stats of the daemon.
+//! Stdio is not used for either, nothing guarantees the libraries never read or print to it.
+
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use libstore::store::protocol::WorkerOp;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncBufReadExt;
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+
+use crate::error::{CommandError, CommandResult};
+use crate::stats;
+
+/// File descriptor of a worker on which it writes its `Report`s
+pub const REPORT_FD: RawFd = 3;
+
+/// File descriptor of a worker on which it serves its client
+pub const CLIENT_FD: RawFd = 4;
+
+/// The client a worker serves, checked by the daemon before the worker is started
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub uid: u32,
+    pub user: String,
+    pub trusted: bool,
+}
+
+/// Status of an op, written by a worker to `REPORT_FD` as one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Report {
+    Started { op: u32 },
+    Finished { op: u32, micros: u64, success: bool },
+}
+
+impl Report {
+    /// Pass the report on to the stats of the daemon
+    fn apply(&self, observer: &stats::Observer) {
+        use libstore::connection::Observer;
+
+        match *self {
+            Report::Started { op } => observer.op_started(WorkerOp::from(op)),
+            Report::Finished {
+                op,
+                micros,
+                success,
+            } => observer.finished(WorkerOp::from(op), Duration::from_micros(micros), success),
+        }
+    }
+}
+
+/// Observer of a worker, writes the `Report`s for the daemon to `REPORT_FD`
+pub struct Reporter {
+    file: std::fs::File,
+}
+
+impl Reporter {
+    /// Take over `REPORT_FD`, which the daemon set up when it started us
+    pub fn new() -> CommandResult<Self> {
+        // builders we start must not keep the channel open
+        if unsafe { libc::fcntl(REPORT_FD, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            file: unsafe { std::fs::File::from_raw_fd(REPORT_FD) },
+        })
+    }
+
+    fn write(&self, report: &Report) {
+        let result = serde_json::to_vec(report)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                // a single write, the daemon never sees half of a report
+                (&self.file).write_all(&line)
+            });
+        if let Err(e) = result {
+            warn!("could not report to the daemon: {}", e);
+        }
+    }
+}
+
+impl libstore::connection::Observer for Reporter {
+    fn op_started(&self, op: WorkerOp) {
+        self.write(&Report::Started { op: op as u32 });
+    }
+
+    fn op_finished(&self, report: &libstore::connection::OpReport) {
+        self.write(&Report::Finished {
+            op: report.op as u32,
+            micros: report.duration.as_micros() as u64,
+            success: report.error.is_none(),
+        });
+    }
+}
+
+/// Start a worker process for `stream` and wait until it exits.
+/// The worker is asked to stop once `shutdown` turns true and killed if this future is dropped.
+/// Ops the worker reports are passed on to `observer`.
+pub async fn spawn(
+    stream: UnixStream,
+    client: &Client,
+    config_file: &Path,
+    overrides: &[(String, String)],
+    store: &str,
+    mut shutdown: watch::Receiver<bool>,
+    observer: &stats::Observer,
+) -> CommandResult<()> {
+    let mut cmd = tokio::process::Command::new(std::env::current_exe()?);
+    cmd.arg("--worker").arg("--config").arg(config_file);
+    for (name, value) in overrides {
+        cmd.arg("--option").arg(name).arg(value);
+    }
+    cmd.arg("--store")
+        .arg(store)
+        .arg("--worker-uid")
+        .arg(client.uid.to_string())
+        .arg("--worker-user")
+        .arg(&client.user);
+    if client.trusted {
+        cmd.arg("--worker-trusted");
+    }
+
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true);
+
+    // both ends are close-on-exec, only the copies made in the child survive the exec
+    let (reports, worker_reports) = std::os::unix::net::UnixStream::pair()?;
+    let fds = [
+        (worker_reports.as_raw_fd(), REPORT_FD),
+        (stream.as_raw_fd(), CLIENT_FD),
+    ];
+    unsafe {
+        cmd.pre_exec(move || {
+            // move them out of the way first, a source may be the target of the other
+            let mut moved = [0; 2];
+            for (i, (from, _)) in fds.iter().enumerate() {
+                moved[i] = libc::fcntl(*from, libc::F_DUPFD_CLOEXEC, 10);
+                if moved[i] == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            // dup2 clears FD_CLOEXEC on the target
+            for (i, (_, to)) in fds.iter().enumerate() {
+                if libc::dup2(moved[i], *to) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                libc::close(moved[i]);
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = cmd.spawn()?;
+    // close our copies, the client has to see EOF once the worker exits
+    drop(cmd);
+    drop(stream);
+    drop(worker_reports);
+
+    let pid = child.id();
+    debug!("started worker {:?} for user {}", pid, client.user);
+
+    reports.set_nonblocking(true)?;
+    let mut reports = tokio::io::BufReader::new(UnixStream::from_std(reports)?).lines();
+    let mut reading = true;
+    let mut watching = true;
+    let status = loop {
+        tokio::select! {
+            status = child.wait(), if !reading => break status?,
+            line = reports.next_line(), if reading => match line {
+                Ok(Some(line)) => match serde_json::from_str::<Report>(&line) {
+                    Ok(report) => report.apply(observer),
+                    Err(e) => warn!("invalid report of worker {:?}: {}", pid, e),
+                },
+                Ok(None) => reading = false,
+                Err(e) => {
+                    warn!("could not read the reports of worker {:?}: {}", pid, e);
+                    reading = false;
+                }
+            },
+            changed = shutdown.changed(), if watching => {
+                if changed.is_err() {
+                    watching = false;
+                } else if *shutdown.borrow() {
+                    watching = false;
+                    if let Some(pid) = pid {
+                        trace!("asking worker {} to stop", pid);
+                        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                    }
+                }
+            }
+        }
+    };
+
+    if status.success() {
+        debug!("worker {:?} exited", pid);
+        Ok(())
+    } else {
+        Err(CommandError::Worker { status })
+    }
+}
+
+/// Take over the client socket the daemon passed to a worker on `CLIENT_FD`
+pub fn client_stream() -> CommandResult<UnixStream> {
+    // builders we start must not get hold of the client
+    if unsafe { libc::fcntl(CLIENT_FD, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(CLIENT_FD) };
+    stream.set_nonblocking(true)?;
+    Ok(UnixStream::from_std(stream)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Report;
+    use crate::connections::Connections;
+    use crate::stats::{Observer, Stats};
+    use libstore::store::protocol::WorkerOp;
+
+    #[test]
+    fn reports() {
+        let report = Report::Finished {
+            op: WorkerOp::WopIsValidPath as u32,
+            micros: 500,
+            success: false,
+        };
+        let line = serde_json::to_string(&report).unwrap();
+        assert_eq!(
+            line,
+            r#"{"event":"finished","op":1,"micros":500,"success":false}"#
+        );
+
+        let observer = Observer {
+            id: 0,
+            connections: Connections::new(),
+            stats: Stats::new(),
+        };
+        serde_json::from_str::<Report>(&line)
+            .unwrap()
+            .apply(&observer);
+        let ops = observer.stats.ops();
+        assert_eq!(ops["WopIsValidPath"].count, 1);
+        assert_eq!(ops["WopIsValidPath"].errors, 1);
+    }
+}
@@ -0,0 +1,23 @@
+//! Helpers for the tests running the daemon binary
+
+use std::path::PathBuf;
+
+/// Encode `values` the way the worker protocol sends numbers
+pub fn u64s(values: &[u64]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Arguments of `WopSetOptions`, without any overrides
+pub const SET_OPTIONS: [u64; 13] = [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0];
+
+/// Create an empty local store in a fresh directory
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nix-test-daemon-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("store")).unwrap();
+    std::fs::create_dir_all(dir.join("var/nix/db")).unwrap();
+    dir
+}
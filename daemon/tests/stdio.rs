@@ -0,0 +1,72 @@
+//! Runs `nix-daemon --stdio` without a daemon to forward to, so it serves the client itself.
+//! Its stdout is the protocol stream and must not carry anything else.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use libstore::connection::{PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2};
+use libstore::source::STDERR;
+use libstore::store::protocol::WorkerOp;
+
+mod common;
+use common::{u64s, SET_OPTIONS};
+
+#[test]
+fn only_protocol_on_stdout() {
+    let dir = common::test_dir("stdio");
+    let config = dir.join("nix.conf");
+    std::fs::write(
+        &config,
+        format!(
+            "store = file://{0}/\nnix-daemon-socket-file = {0}/no-daemon\n",
+            dir.display()
+        ),
+    )
+    .unwrap();
+
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_nix-daemon"))
+        .arg("--stdio")
+        .arg("--config")
+        .arg(&config)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut input = u64s(&[WORKER_MAGIC_1 as u64, PROTOCOL_VERSION as u64, 0, 0]);
+    for _ in 0..2 {
+        input.extend(u64s(&[WorkerOp::WopSetOptions as u64]));
+        input.extend(u64s(&SET_OPTIONS));
+    }
+    let mut stdin = daemon.stdin.take().unwrap();
+    stdin.write_all(&input).unwrap();
+    drop(stdin);
+
+    let mut stdout = daemon.stdout.take().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).unwrap();
+        tx.send(output).unwrap();
+    });
+    // a print to stdout blocks on the lock held by the stdio proxy
+    let output = rx.recv_timeout(std::time::Duration::from_secs(30));
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+    let output = output.expect("the daemon did not finish the session");
+
+    let last = STDERR::LAST as u64;
+    assert_eq!(
+        output,
+        u64s(&[
+            WORKER_MAGIC_2 as u64,
+            PROTOCOL_VERSION as u64,
+            last, // end of the handshake
+            last,
+            last,
+        ])
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
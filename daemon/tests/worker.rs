@@ -0,0 +1,104 @@
+//! Runs the daemon with worker processes, the ops they serve have to reach its stats.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use libstore::connection::{PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2};
+use libstore::source::STDERR;
+use libstore::store::protocol::WorkerOp;
+
+mod common;
+use common::{u64s, SET_OPTIONS};
+
+/// Stops the daemon, also when the test fails
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        unsafe { libc::kill(self.0.id() as libc::pid_t, libc::SIGTERM) };
+        let _ = self.0.wait();
+    }
+}
+
+/// Connect to `socket` once the daemon created it
+fn connect(socket: &Path) -> UnixStream {
+    let started = Instant::now();
+    loop {
+        match UnixStream::connect(socket) {
+            Ok(v) => return v,
+            Err(e) if started.elapsed() > Duration::from_secs(10) => {
+                panic!("could not connect to {}: {}", socket.display(), e)
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// Count of `op` in the status reported on the admin socket
+fn op_count(admin: &Path, op: WorkerOp) -> u64 {
+    let mut status = String::new();
+    connect(admin).read_to_string(&mut status).unwrap();
+    let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+    status["ops"][format!("{:?}", op)]["count"]
+        .as_u64()
+        .unwrap_or(0)
+}
+
+#[test]
+fn ops_of_workers_are_counted() {
+    let dir = common::test_dir("worker");
+    let socket = dir.join("socket");
+    let admin = dir.join("admin");
+    let config = dir.join("nix.conf");
+    std::fs::write(
+        &config,
+        format!(
+            "store = file://{}/\nnix-daemon-socket-file = {}\ndaemon-admin-socket-file = {}\ndaemon-worker-processes = true\n",
+            dir.display(),
+            socket.display(),
+            admin.display()
+        ),
+    )
+    .unwrap();
+
+    let daemon = Command::new(env!("CARGO_BIN_EXE_nix-daemon"))
+        .arg("--config")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let daemon = Daemon(daemon);
+
+    let mut client = connect(&socket);
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut input = u64s(&[WORKER_MAGIC_1 as u64, PROTOCOL_VERSION as u64, 0, 0]);
+    input.extend(u64s(&[WorkerOp::WopSetOptions as u64]));
+    input.extend(u64s(&SET_OPTIONS));
+    client.write_all(&input).unwrap();
+
+    let last = STDERR::LAST as u64;
+    let expected = u64s(&[WORKER_MAGIC_2 as u64, PROTOCOL_VERSION as u64, last, last]);
+    let mut output = vec![0; expected.len()];
+    client.read_exact(&mut output).unwrap();
+    assert_eq!(output, expected);
+    drop(client);
+
+    // the worker reports the op, the daemon reads it asynchronously
+    let started = Instant::now();
+    while op_count(&admin, WorkerOp::WopSetOptions) != 1 {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the op of the worker was not counted"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    drop(daemon);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
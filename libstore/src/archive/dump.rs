@@ -0,0 +1,226 @@
+//! Serialisation of a filesystem tree into a NAR
+
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use futures::future::LocalFutureObj;
+use log::*;
+use tokio::io::AsyncReadExt;
+
+use super::NAR_VERSION_MAGIC_1;
+use crate::error::NarError;
+use crate::source::AsyncWrite;
+use crate::store::Hash;
+
+/// File contents are streamed in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Write the tree at `path` as a NAR to `sink`
+pub async fn dump_path<W: ?Sized + AsyncWrite>(path: &str, sink: &W) -> Result<(), NarError> {
+    trace!("dumping {} as nar", path);
+    sink.write_string(NAR_VERSION_MAGIC_1).await?;
+    dump_node(Path::new(path), sink).await
+}
+
+/// Hash and size of the NAR serialisation of `path`
+pub async fn hash_path(path: &str) -> Result<(Hash, u64), NarError> {
+    let sink = HashSink {
+        hasher: Mutex::new((0, ring::digest::Context::new(&ring::digest::SHA256))),
+    };
+    dump_path(path, &sink).await?;
+
+    let (size, hasher) = sink.hasher.into_inner().unwrap();
+    Ok((Hash::from_sha256_vec(hasher.finish().as_ref())?, size))
+}
+
+struct HashSink {
+    hasher: Mutex<(u64, ring::digest::Context)>,
+}
+
+impl AsyncWrite for HashSink {
+    fn write<'a>(&'a self, buf: &'a [u8]) -> LocalFutureObj<'a, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut hasher = self.hasher.lock().unwrap();
+            hasher.0 += buf.len() as u64;
+            hasher.1.update(buf);
+            Ok(buf.len())
+        }))
+    }
+}
+
+fn dump_node<'a, W: ?Sized + AsyncWrite>(
+    path: &'a Path,
+    sink: &'a W,
+) -> LocalFutureObj<'a, Result<(), NarError>> {
+    LocalFutureObj::new(Box::new(async move {
+        let meta = tokio::fs::symlink_metadata(path).await?;
+        let file_type = meta.file_type();
+
+        sink.write_string("(").await?;
+        if file_type.is_file() {
+            sink.write_string("type").await?;
+            sink.write_string("regular").await?;
+            if meta.permissions().mode() & 0o100 != 0 {
+                sink.write_string("executable").await?;
+                sink.write_string("").await?;
+            }
+            sink.write_string("contents").await?;
+            dump_contents(path, meta.len(), sink).await?;
+        } else if file_type.is_dir() {
+            sink.write_string("type").await?;
+            sink.write_string("directory").await?;
+
+            // entries have to be sorted by their bytes
+            let mut names = Vec::new();
+            let mut entries = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                names.push(entry.file_name());
+            }
+            names.sort();
+
+            for name in names {
+                sink.write_string("entry").await?;
+                sink.write_string("(").await?;
+                sink.write_string("name").await?;
+                sink.write_os_string(name.as_bytes()).await?;
+                sink.write_string("node").await?;
+                dump_node(&path.join(&name), sink).await?;
+                sink.write_string(")").await?;
+            }
+        } else if file_type.is_symlink() {
+            let target = tokio::fs::read_link(path).await?;
+            sink.write_string("type").await?;
+            sink.write_string("symlink").await?;
+            sink.write_string("target").await?;
+            sink.write_os_string(target.as_os_str().as_bytes()).await?;
+        } else {
+            return Err(NarError::UnsupportedFileType {
+                path: path.display().to_string(),
+            });
+        }
+        sink.write_string(")").await?;
+
+        Ok(())
+    }))
+}
+
+async fn dump_contents<W: ?Sized + AsyncWrite>(
+    path: &Path,
+    len: u64,
+    sink: &W,
+) -> Result<(), NarError> {
+    sink.write_u64(len).await?;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let want = std::cmp::min(left, CHUNK_SIZE as u64) as usize;
+        let read = file.read(&mut buf[..want]).await?;
+        if read == 0 {
+            // the length is already sent, a shorter file would corrupt the NAR
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("file '{}' shrunk while dumping it", path.display()),
+            )
+            .into());
+        }
+        sink.write_all(&buf[..read]).await?;
+        left -= read as u64;
+    }
+
+    sink.write_padding(len as usize).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::dump_path;
+    use crate::archive::NarParser;
+    use crate::source::test::Connection;
+    use crate::store::mock_store::MockStore;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nix-test-dump-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn dump(path: &std::path::Path) -> Vec<u8> {
+        let con = Connection::new_empty(false);
+        dump_path(path.to_str().unwrap(), &con).await.unwrap();
+        let nar = con.writer.lock().unwrap().get_ref().clone();
+        nar
+    }
+
+    #[tokio::test]
+    async fn dump_file() {
+        let dir = temp_dir("file");
+        let file = dir.join("file");
+        std::fs::write(&file, "hello\n").unwrap();
+
+        assert_eq!(dump(&file).await, crate::archive::dump_data(b"hello\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hash_path() {
+        let dir = temp_dir("hash");
+        let file = dir.join("file");
+        std::fs::write(&file, "hello\n").unwrap();
+
+        let nar = crate::archive::dump_data(b"hello\n");
+        let hash = ring::digest::digest(&ring::digest::SHA256, &nar);
+        assert_eq!(
+            super::hash_path(file.to_str().unwrap()).await.unwrap(),
+            (
+                crate::store::Hash::from_sha256_vec(hash.as_ref()).unwrap(),
+                nar.len() as u64
+            )
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = temp_dir("round-trip");
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("file"), "hello\n").unwrap();
+        std::fs::write(root.join("exe"), "execute\n").unwrap();
+        std::fs::set_permissions(root.join("exe"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("exe", root.join("exe_symlink")).unwrap();
+        // larger than a chunk and not a multiple of 8
+        let large: Vec<u8> = (0..70_003).map(|v| v as u8).collect();
+        std::fs::write(root.join("sub").join("large"), &large).unwrap();
+
+        let nar = dump(&root).await;
+
+        let store = Arc::new(MockStore::new());
+        let reader = Connection::new(nar, false);
+        let parser = NarParser::new("/mock/dir", &reader, Box::new(store.clone()));
+        parser.parse().await.unwrap();
+
+        assert!(store.dir_exists("/mock/dir"));
+        assert!(store.dir_exists("/mock/dir/sub"));
+        assert_eq!(store.file_as_string("/mock/dir/file"), "hello\n");
+        assert!(!store.is_file_executable("/mock/dir/file"));
+        assert_eq!(store.file_as_string("/mock/dir/exe"), "execute\n");
+        assert!(store.is_file_executable("/mock/dir/exe"));
+        assert_eq!(store.symlinks_points_at("/mock/dir/exe_symlink"), "exe");
+        assert_eq!(
+            store.file_as_string("/mock/dir/sub/large"),
+            String::from_utf8_lossy(&large)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
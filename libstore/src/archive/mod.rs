@@ -17,6 +17,9 @@ use std::sync::Mutex;
 
 pub const NAR_VERSION_MAGIC_1: &'static str = "nix-archive-1";
 
+mod dump;
+pub use dump::{dump_path, hash_path};
+
 /// Returned as succesfully parsed nar archive
 #[derive(Debug)]
 pub struct NarResult {}
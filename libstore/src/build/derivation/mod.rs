@@ -21,7 +21,7 @@ pub struct ParsedDerivation {
 impl ParsedDerivation {
     pub fn new(drv_path: StorePath, derivation: Derivation) -> Result<Self, StoreError> {
         if derivation.env.contains_key("__json") {
-            unimplemented!("__json attribute in derivation");
+            crate::unimplemented!("__json attribute in derivation");
         }
 
         Ok(Self {
@@ -118,6 +118,52 @@ impl Derivation {
         Self::from_str(&drv, store)
     }
 
+    /// Read a basic derivation in the format of the worker protocol, as sent by remote builders
+    pub async fn from_wire<C: ?Sized + crate::source::AsyncRead>(
+        con: &C,
+        store: &dyn Store,
+    ) -> Result<Self, StoreError> {
+        let mut ret = Self::new();
+
+        let mut outputs = Vec::new();
+        for _ in 0..con.read_u64().await? {
+            let name = con.read_string().await?;
+            let path = con.read_string().await?;
+            let algo = con.read_string().await?;
+            let hash = con.read_string().await?;
+            outputs.push((name, path, algo, hash));
+        }
+        let input_srcs = con.read_strings().await?;
+        ret.platform = con.read_string().await?;
+        ret.builder = con.read_string().await?;
+        ret.args = con.read_strings().await?;
+
+        let env = con.read_u64().await?;
+        for _ in 0..env {
+            let name = con.read_string().await?;
+            let value = con.read_string().await?;
+            ret.env.insert(name, value);
+        }
+
+        // paths are checked once the whole derivation is read, invalid ones leave the stream usable
+        for (name, path, algo, hash) in outputs {
+            let output = DerivationOutput {
+                path: store.parse_store_path(&path)?,
+                hash: if algo.is_empty() && hash.is_empty() {
+                    None
+                } else {
+                    Some(format!("{}:{}", algo, hash))
+                },
+            };
+            ret.outputs.insert(name, output);
+        }
+        for v in input_srcs {
+            ret.input_srcs.push(store.parse_store_path(&v)?);
+        }
+
+        Ok(ret)
+    }
+
     pub fn from_str(str: &str, store: &dyn Store) -> Result<Self, StoreError> {
         let ast = Ast::from_str(str)?;
 
@@ -133,6 +179,11 @@ impl Derivation {
                     msg: format!("Derivation Tuple has {} elements", v.len()),
                 });
             }
+            if v.len() < 7 {
+                return Err(StoreError::InvalidDerivation {
+                    msg: format!("Derivation Tuple has only {} elements", v.len()),
+                });
+            }
             if let AstNode::Array(v) = &v[0] {
                 ret.outputs = Self::parse_outputs(v, store)?;
             } else {
@@ -144,6 +195,11 @@ impl Derivation {
             if let AstNode::Array(v) = &v[1] {
                 for v in v {
                     if let AstNode::Tuple(v) = v {
+                        if v.len() != 2 {
+                            return Err(StoreError::InvalidDerivation {
+                                msg: "inputs element is not a pair".to_string(),
+                            });
+                        }
                         let path = store.parse_store_path(&v[0].to_string()?)?;
                         let outputs: Result<Vec<String>, StoreError> = v[1]
                             .to_array()?
@@ -254,16 +310,18 @@ impl DerivationOutput {
             });
         }
 
+        // fixed output derivations carry the hash algorithm and the expected hash
+        let algo = ast[2].to_string()?;
+        let hash = ast[3].to_string()?;
         let ret = Self {
             path: store.parse_store_path(&ast[1].to_string()?)?,
-            hash: None,
+            hash: if algo.is_empty() && hash.is_empty() {
+                None
+            } else {
+                Some(format!("{}:{}", algo, hash))
+            },
         };
 
-        if ast[2].to_string()? != "" || ast[3].to_string()? != "" {
-            log::warn!("output [2..3] is not '\"\"'");
-            unreachable!("output [2..3] not \"\"");
-        }
-
         let name = ast[0].to_string()?;
         Ok((name, ret))
     }
@@ -273,6 +331,59 @@ impl DerivationOutput {
 mod test {
     pub const HELLO_DRV: &str = r#"Derive([("out","/nix/store/gfri16c7bbgfjj44c00q4sfw5wb5i5g9-hello-2.10","","")],[("/nix/store/130cylf8ms564hb4h7a8jqmdnqaz4xc2-bash-4.4-p23.drv",["out"]),("/nix/store/jwwz66zxkzm7ymcpfs3h26x39kk3rvm6-hello-2.10.tar.gz.drv",["out"]),("/nix/store/v0d85x08ww9xdgghp6my7rc0m3lzkfy4-stdenv-linux.drv",["out"])],["/nix/store/yigg1q0y7ynnm0mjl60341aad62sngpd-default-builder.sh"],"x86_64-linux","/nix/store/yxdxssjvldpx2gh6d9ggv0a9dg1v6z3i-bash-4.4-p23/bin/bash",["-e","/nix/store/yigg1q0y7ynnm0mjl60341aad62sngpd-default-builder.sh"],[("buildInputs",""),("builder","/nix/store/yxdxssjvldpx2gh6d9ggv0a9dg1v6z3i-bash-4.4-p23/bin/bash"),("configureFlags",""),("depsBuildBuild",""),("depsBuildBuildPropagated",""),("depsBuildTarget",""),("depsBuildTargetPropagated",""),("depsHostHost",""),("depsHostHostPropagated",""),("depsTargetTarget",""),("depsTargetTargetPropagated",""),("doCheck","1"),("doInstallCheck",""),("name","hello-2.10"),("nativeBuildInputs",""),("out","/nix/store/gfri16c7bbgfjj44c00q4sfw5wb5i5g9-hello-2.10"),("outputs","out"),("patches",""),("pname","hello"),("propagatedBuildInputs",""),("propagatedNativeBuildInputs",""),("src","/nix/store/3x7dwzq014bblazs7kq20p9hyzz0qh8g-hello-2.10.tar.gz"),("stdenv","/nix/store/y4rca6a87l2l49p55m2mpnwndma21mkx-stdenv-linux"),("strictDeps",""),("system","x86_64-linux"),("version","2.10")])"#;
 
+    #[tokio::test]
+    async fn from_wire() {
+        fn string(wire: &mut Vec<u8>, v: &str) {
+            wire.extend_from_slice(&(v.len() as u64).to_le_bytes());
+            wire.extend_from_slice(v.as_bytes());
+            wire.resize(wire.len() + (8 - v.len() % 8) % 8, 0);
+        }
+
+        let mut wire = Vec::new();
+        wire.extend_from_slice(&2u64.to_le_bytes());
+        for v in &[
+            "out",
+            "/nix/store/ffffffffffffffffffffffffffffffff-foo",
+            "",
+            "",
+            "src",
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-src",
+            "r:sha256",
+            "abcd",
+        ] {
+            string(&mut wire, v);
+        }
+        wire.extend_from_slice(&1u64.to_le_bytes());
+        string(
+            &mut wire,
+            "/nix/store/zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-builder.sh",
+        );
+        string(&mut wire, "x86_64-linux");
+        string(&mut wire, "/bin/sh");
+        wire.extend_from_slice(&1u64.to_le_bytes());
+        string(&mut wire, "-e");
+        wire.extend_from_slice(&1u64.to_le_bytes());
+        string(&mut wire, "name");
+        string(&mut wire, "foo");
+
+        let con = crate::source::test::Connection::new(wire, false);
+        let store = std::sync::Arc::new(crate::store::mock_store::MockStore::new());
+        let drv = super::Derivation::from_wire(&con, &store).await.unwrap();
+
+        assert_eq!(drv.outputs.len(), 2);
+        assert_eq!(
+            drv.outputs["out"].path.to_string(),
+            "ffffffffffffffffffffffffffffffff-foo"
+        );
+        assert_eq!(drv.outputs["out"].hash, None);
+        assert_eq!(drv.outputs["src"].hash.as_deref(), Some("r:sha256:abcd"));
+        assert_eq!(drv.input_srcs.len(), 1);
+        assert_eq!(drv.platform, "x86_64-linux");
+        assert_eq!(drv.builder, "/bin/sh");
+        assert_eq!(drv.args, vec!["-e"]);
+        assert_eq!(drv.env["name"], "foo");
+    }
+
     #[tokio::test]
     async fn read_basic_drv() {
         let drv = HELLO_DRV;
@@ -284,4 +395,22 @@ mod test {
 
         println!("drv: {:?}", drv);
     }
+
+    #[test]
+    fn invalid_drv() {
+        let store = std::sync::Arc::new(crate::store::mock_store::MockStore::new());
+        assert!(super::Derivation::from_str("Derive()", &store).is_err());
+        assert!(super::Derivation::from_str(
+            r#"Derive([],[("/nix/store/zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-foo.drv")],[],"","",[],[])"#,
+            &store
+        )
+        .is_err());
+
+        // structured attributes are not supported yet
+        let mut drv = super::Derivation::new();
+        drv.env.insert("__json".to_string(), "{}".to_string());
+        let path =
+            crate::store::StorePath::new("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-foo.drv").unwrap();
+        assert!(super::ParsedDerivation::new(path, drv).is_err());
+    }
 }
@@ -0,0 +1,328 @@
+//! Running the builder of a derivation directly on the host, there is no sandbox yet
+
+use std::collections::{HashMap, HashSet};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::LocalFutureObj;
+use log::*;
+use tokio::io::AsyncBufReadExt;
+
+use super::derivation::Derivation;
+use super::user::UserLock;
+use crate::error::StoreError;
+use crate::source::AsyncWrite;
+use crate::store::{BuildOptions, Hash, StorePath};
+
+/// Length of the hash part of a store path
+const HASH_PART_LEN: usize = 32;
+
+/// How the builder exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exit {
+    Success,
+    /// The exit code or signal of the builder
+    Failed(String),
+    /// Which limit of the `BuildOptions` was exceeded
+    TimedOut(String),
+}
+
+/// Create an empty directory for the build of `name` which only its owner can access
+pub fn create_build_dir(name: &str) -> std::io::Result<PathBuf> {
+    let base = std::env::temp_dir();
+    let mut i = 0;
+    loop {
+        let dir = base.join(format!("nix-build-{}-{}-{}", name, std::process::id(), i));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => i += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The environment of the builder, nothing is inherited from the daemon
+fn environment(drv: &Derivation, store_dir: &str, build_dir: &Path) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+    // the builder should not rely on the user's PATH or HOME
+    env.insert("PATH".to_string(), "/path-not-set".to_string());
+    env.insert("HOME".to_string(), "/homeless-shelter".to_string());
+    env.insert("NIX_STORE".to_string(), store_dir.to_string());
+    let cores = crate::CONFIG.read().unwrap().cores;
+    env.insert("NIX_BUILD_CORES".to_string(), cores.to_string());
+
+    for (k, v) in &drv.env {
+        env.insert(k.clone(), v.clone());
+    }
+
+    let build_dir = build_dir.display().to_string();
+    for k in &["NIX_BUILD_TOP", "TMPDIR", "TEMPDIR", "TMP", "TEMP", "PWD"] {
+        env.insert(k.to_string(), build_dir.clone());
+    }
+    env.insert("NIX_LOG_FD".to_string(), "2".to_string());
+    env.insert("TERM".to_string(), "xterm-256color".to_string());
+    env
+}
+
+/// Run the builder of `drv` in `build_dir`, as `user` if given, and log its output
+pub async fn run(
+    name: &str,
+    drv: &Derivation,
+    store_dir: &str,
+    build_dir: &Path,
+    user: Option<&UserLock>,
+    options: &BuildOptions,
+) -> Result<Exit, StoreError> {
+    // stdout and stderr share one socket, so the lines stay in order
+    let (log, builder_log) = std::os::unix::net::UnixStream::pair()?;
+    let builder_err = builder_log.try_clone()?;
+
+    let mut command = tokio::process::Command::new(&drv.builder);
+    command
+        .args(&drv.args)
+        .env_clear()
+        .envs(environment(drv, store_dir, build_dir))
+        .current_dir(build_dir)
+        .stdin(Stdio::null())
+        .stdout(unsafe { Stdio::from_raw_fd(builder_log.into_raw_fd()) })
+        .stderr(unsafe { Stdio::from_raw_fd(builder_err.into_raw_fd()) })
+        .kill_on_drop(true);
+    if let Some(user) = user {
+        let (uid, gid) = (user.get_uid(), user.get_gid());
+        let groups = user.get_groups().to_vec();
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setgroups(groups.len() as _, groups.as_ptr()) == -1
+                    || libc::setgid(gid) == -1
+                    || libc::setuid(uid) == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let mut child = command.spawn()?;
+    // the builder has to hold the only write ends of the log, or it never ends
+    drop(command);
+
+    log.set_nonblocking(true)?;
+    let mut log = tokio::io::BufReader::new(tokio::net::UnixStream::from_std(log)?).split(b'\n');
+
+    let started = tokio::time::Instant::now();
+    let timeout = limit(options.timeout);
+    let silence = limit(options.max_silent_time);
+    let timed_out = || {
+        let left = timeout.map(|v| v.checked_sub(started.elapsed()).unwrap_or_default());
+        match (left, silence) {
+            (Some(left), Some(silence)) if silence < left => (
+                Some(silence),
+                format!(
+                    "timed out after {} seconds of silence",
+                    options.max_silent_time
+                ),
+            ),
+            (Some(left), _) => (
+                Some(left),
+                format!("timed out after {} seconds", options.timeout),
+            ),
+            (None, silence) => (
+                silence,
+                format!(
+                    "timed out after {} seconds of silence",
+                    options.max_silent_time
+                ),
+            ),
+        }
+    };
+
+    loop {
+        let line = match timed_out() {
+            (Some(wait), msg) => match tokio::time::timeout(wait, log.next_segment()).await {
+                Ok(v) => v?,
+                Err(_) => {
+                    child.kill().await?;
+                    return Ok(Exit::TimedOut(msg));
+                }
+            },
+            (None, _) => log.next_segment().await?,
+        };
+        match line {
+            Some(line) => info!("{}> {}", name, String::from_utf8_lossy(&line)),
+            None => break,
+        }
+    }
+
+    // the builder may close its log early, the timeout still applies
+    let status = match timeout.map(|v| v.checked_sub(started.elapsed()).unwrap_or_default()) {
+        Some(left) => match tokio::time::timeout(left, child.wait()).await {
+            Ok(v) => v?,
+            Err(_) => {
+                child.kill().await?;
+                return Ok(Exit::TimedOut(format!(
+                    "timed out after {} seconds",
+                    options.timeout
+                )));
+            }
+        },
+        None => child.wait().await?,
+    };
+
+    Ok(match (status.code(), status.signal()) {
+        (Some(0), _) => Exit::Success,
+        (Some(code), _) => Exit::Failed(format!("exit code {}", code)),
+        (None, Some(signal)) => Exit::Failed(format!("signal {}", signal)),
+        (None, None) => Exit::Failed("unknown status".to_string()),
+    })
+}
+
+fn limit(seconds: u64) -> Option<Duration> {
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+/// Give a build output the metadata of a store path: read-only, mtime 1 and owned by us
+pub fn canonicalise(path: &Path) -> std::io::Result<()> {
+    let meta = std::fs::symlink_metadata(path)?;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if (meta.uid() != uid || meta.gid() != gid)
+        && unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    if !meta.file_type().is_symlink() {
+        let mode = if meta.is_dir() || meta.mode() & 0o100 != 0 {
+            0o555
+        } else {
+            0o444
+        };
+        if meta.mode() & 0o7777 != mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+
+    if meta.is_dir() {
+        for v in std::fs::read_dir(path)? {
+            canonicalise(&v?.path())?;
+        }
+    }
+
+    let times = [libc::timespec {
+        tv_sec: 1,
+        tv_nsec: 0,
+    }; 2];
+    if unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } == -1
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Check the output of a fixed-output derivation against its `algo:hash` or `r:algo:hash`,
+/// returns the hash of the output if it differs
+pub async fn check_fixed_output(path: &str, expected: &str) -> Result<Option<Hash>, StoreError> {
+    let (recursive, hash) = match expected.strip_prefix("r:") {
+        Some(v) => (true, v),
+        None => (false, expected),
+    };
+    let hash = match hash.strip_prefix("sha256:") {
+        Some(v) => Hash::from_sha256(v)?,
+        None => crate::unimplemented!("fixed-output hash '{}'", expected),
+    };
+
+    let actual = if recursive {
+        crate::archive::hash_path(path).await?.0
+    } else {
+        let data = tokio::fs::read(path).await?;
+        Hash::from_sha256_vec(ring::digest::digest(&ring::digest::SHA256, &data).as_ref())?
+    };
+    Ok(if actual == hash { None } else { Some(actual) })
+}
+
+/// The paths of `candidates` whose hash part occurs in the NAR serialisation of `path`
+pub async fn scan_references(
+    path: &str,
+    candidates: &[StorePath],
+) -> Result<Vec<StorePath>, StoreError> {
+    let sink = ReferenceSink {
+        hashes: candidates
+            .iter()
+            .map(|v| v.hash_part().into_bytes())
+            .collect(),
+        state: Mutex::new((Vec::new(), HashSet::new())),
+    };
+    crate::archive::dump_path(path, &sink).await?;
+
+    let found = sink.state.into_inner().unwrap().1;
+    Ok(candidates
+        .iter()
+        .filter(|v| found.contains(v.hash_part().as_bytes()))
+        .cloned()
+        .collect())
+}
+
+struct ReferenceSink {
+    hashes: HashSet<Vec<u8>>,
+    /// The end of the previous write, a hash may span two writes, and the hashes found
+    state: Mutex<(Vec<u8>, HashSet<Vec<u8>>)>,
+}
+
+impl AsyncWrite for ReferenceSink {
+    fn write<'a>(&'a self, buf: &'a [u8]) -> LocalFutureObj<'a, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut state = self.state.lock().unwrap();
+            let (tail, found) = &mut *state;
+            tail.extend_from_slice(buf);
+            for window in tail.windows(HASH_PART_LEN) {
+                if self.hashes.contains(window) {
+                    found.insert(window.to_vec());
+                }
+            }
+            let keep = tail.len().saturating_sub(HASH_PART_LEN - 1);
+            tail.drain(..keep);
+            Ok(buf.len())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::StorePath;
+
+    #[tokio::test]
+    async fn scan_references() {
+        let dir = std::env::temp_dir().join(format!("nix-scan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let foo = StorePath::new("ffffffffffffffffffffffffffffffff-foo").unwrap();
+        let bar = StorePath::new("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bar").unwrap();
+        // longer than one chunk of the dump, so the hash is split between two writes
+        let mut data = vec![b'x'; 64 * 1024 - 10];
+        data.extend_from_slice(b"/nix/store/ffffffffffffffffffffffffffffffff-foo");
+        std::fs::write(dir.join("file"), data).unwrap();
+
+        let found = super::scan_references(&dir.display().to_string(), &[foo.clone(), bar])
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, vec![foo]);
+    }
+}
@@ -4,6 +4,8 @@ pub mod user;
 
 pub mod worker;
 
+pub mod local;
+
 pub mod derivation;
 
 //pub mod goal;
@@ -97,6 +97,10 @@ impl UserLock {
     pub fn get_gid(&self) -> gid_t {
         self.gid
     }
+
+    pub fn get_groups(&self) -> &[gid_t] {
+        &self.supplementary_gids
+    }
 }
 
 impl Drop for UserLock {
@@ -1,3 +1,9 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of build slots occupied by the workers of this process.  This includes local builds and
+/// substitutions but not remote builds via the build hook.
+static NR_LOCAL_BUILDS: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Worker {
     /*/* Note: the worker should only have strong pointers to the
        top-level goals. */
@@ -27,8 +33,7 @@ pub struct Worker {
 
     /* Cache for pathContentsGood(). */
     std::map<StorePath, bool> pathContentsGoodCache;*/
-    /// Number of build slots occupied.  This includes local builds and
-    /// substitutions but not remote builds via the build hook.
+    /// Build slots occupied by this worker
     nr_local_builds: usize,
 
     ///  Last time the goals in `waitingForAWhile' where woken up.
@@ -151,7 +156,33 @@ impl Worker {
         }
     }
 
-    pub fn get_nr_local_builds(&self) -> usize {
-        self.nr_local_builds
+    /// Returns the number of local build and substitution processes currently
+    /// running in this process (but not remote builds via the build hook).
+    pub fn get_nr_local_builds() -> usize {
+        NR_LOCAL_BUILDS.load(Ordering::Relaxed)
+    }
+
+    /// Registers a running child process. `in_build_slot` means that the process
+    /// counts towards the jobs limit.
+    pub fn child_started(&mut self, in_build_slot: bool) {
+        if in_build_slot {
+            self.nr_local_builds += 1;
+            NR_LOCAL_BUILDS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Unregisters a running child process
+    pub fn child_terminated(&mut self, in_build_slot: bool) {
+        if in_build_slot && self.nr_local_builds > 0 {
+            self.nr_local_builds -= 1;
+            NR_LOCAL_BUILDS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // release the slots of children we did not see terminate
+        NR_LOCAL_BUILDS.fetch_sub(self.nr_local_builds, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,57 @@
+//! Limits the daemon enforces per user
+
+use std::path::Path;
+
+use log::trace;
+
+use crate::gc::lock::{lock_file, LockType};
+
+/// One of the concurrent builds a user may run.
+/// Slots are lock files, so they are shared with worker processes and released when the holder dies.
+#[derive(Debug)]
+pub struct BuildSlot {
+    _file: std::fs::File,
+}
+
+impl BuildSlot {
+    /// Take a free slot of the `max` slots of `uid` in `dir`.
+    /// Returns `None` if all slots are in use.
+    pub fn acquire(dir: &Path, uid: u32, max: usize) -> std::io::Result<Option<Self>> {
+        std::fs::create_dir_all(dir)?;
+
+        for n in 0..max {
+            let file = std::fs::File::create(dir.join(format!("{}-{}", uid, n)))?;
+            if lock_file(&file, LockType::Write, false)? {
+                trace!("got build slot {} of uid {}", n, uid);
+                return Ok(Some(Self { _file: file }));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BuildSlot;
+
+    #[test]
+    fn build_slots() {
+        let dir = std::env::temp_dir().join(format!("nix-test-build-slots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let first = BuildSlot::acquire(&dir, 1000, 2).unwrap();
+        let second = BuildSlot::acquire(&dir, 1000, 2).unwrap();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(BuildSlot::acquire(&dir, 1000, 2).unwrap().is_none());
+
+        // other users have their own slots
+        assert!(BuildSlot::acquire(&dir, 1001, 2).unwrap().is_some());
+
+        drop(first);
+        assert!(BuildSlot::acquire(&dir, 1000, 2).unwrap().is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -12,15 +12,92 @@ use tokio::net::UnixStream;
 
 use crate::error::StoreError;
 use crate::source::{AsyncRead, AsyncWrite, Logger, WORKDONE};
+
+pub mod limits;
+pub mod serve;
+
 type EmptyResult = Result<(), StoreError>;
 
 pub const WORKER_MAGIC_1: u32 = 0x6e697863;
 pub const WORKER_MAGIC_2: u32 = 0x6478696f;
 pub const PROTOCOL_VERSION: u16 = 0x115;
+/// Oldest client version the daemon talks to
+pub const MIN_CLIENT_VERSION: u16 = 0x10a;
+
+pub fn get_protocol_major(version: u16) -> u16 {
+    version & 0xff00
+}
+
+pub fn get_protocol_minor(version: u16) -> u16 {
+    version & 0x00ff
+}
+
+/// Server side of the worker protocol handshake.
+/// Returns the version both sides use for the rest of the connection, `min(client, PROTOCOL_VERSION)`.
+pub async fn handshake<C>(con: &C) -> Result<u16, StoreError>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    let magic = con.read_u64().await?;
+    if magic != WORKER_MAGIC_1 as u64 {
+        return Err(StoreError::InvalidMagic {});
+    }
+
+    con.write_u64(WORKER_MAGIC_2 as u64).await?;
+    con.write_u64(PROTOCOL_VERSION as u64).await?;
+
+    let client_version = con.read_u64().await?;
+    if client_version > u16::MAX as u64
+        || get_protocol_major(client_version as u16) != get_protocol_major(PROTOCOL_VERSION)
+    {
+        return Err(StoreError::UnsupportedVersion {
+            version: client_version,
+        });
+    }
+    let client_version = client_version as u16;
+    if client_version < MIN_CLIENT_VERSION {
+        return Err(StoreError::ClientTooOld {
+            version: client_version,
+        });
+    }
+    let version = std::cmp::min(client_version, PROTOCOL_VERSION);
+    trace!(
+        "client version {:#x}, using version {:#x}",
+        client_version,
+        version
+    );
+
+    if get_protocol_minor(version) >= 14 && con.read_u64().await? != 0 {
+        con.read_u64().await?; // obsolete: CPU affinity
+    }
+    if get_protocol_minor(version) >= 11 {
+        con.read_u64().await?; // obsolete: reserveSpace
+    }
+
+    Ok(version)
+}
 
 #[allow(unused_imports)]
 use crate::unimplemented;
 
+/// Outcome of an op, reported to the `Observer`s of the connection
+#[derive(Debug)]
+pub struct OpReport<'a> {
+    pub op: crate::store::protocol::WorkerOp,
+    pub duration: std::time::Duration,
+    /// Set if the op failed
+    pub error: Option<&'a StoreError>,
+    /// Store paths the op added or changed
+    pub paths: &'a [String],
+}
+
+/// Notified about every op a connection performs
+pub trait Observer {
+    fn op_started(&self, op: crate::store::protocol::WorkerOp);
+
+    fn op_finished(&self, report: &OpReport);
+}
+
 #[derive(Debug)]
 struct ClientSettings {
     keep_failed: bool,
@@ -50,6 +127,17 @@ impl ClientSettings {
     }
 }
 
+/// How far the current op got, decides what a failure does to the connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OpState {
+    /// Reading the arguments, nothing was sent yet
+    Reading,
+    /// The log is open, messages and errors can be sent
+    Working,
+    /// STDERR_LAST was sent and the client reads the reply
+    Done,
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 enum Data {
@@ -65,13 +153,27 @@ pub struct Connection {
     uid: u32,
     u_name: String,
 
+    /// Protocol version negotiated in the handshake
+    version: u16,
+
     store: Box<dyn crate::store::BuildStore>,
+
+    /// Set to true when the daemon wants the connection to close after the current operation
+    shutdown: Option<tokio::sync::watch::Receiver<bool>>,
+
+    observers: Vec<Box<dyn Observer>>,
+    /// Store paths changed by the current op
+    affected: Vec<String>,
+    state: OpState,
+
+    /// Largest NAR an untrusted client may upload in one op, 0 means unlimited
+    max_upload_size: u64,
 }
 
 impl Connection {
     pub fn new(
         trusted: bool,
-        client_version: u16,
+        version: u16,
         con: crate::source::Connection,
         store: Box<dyn crate::store::BuildStore>,
         uid: u32,
@@ -79,30 +181,176 @@ impl Connection {
     ) -> Self {
         Self {
             trusted,
+            version,
             con,
             store,
             uid,
             u_name,
+            shutdown: None,
+            observers: Vec::new(),
+            affected: Vec::new(),
+            state: OpState::Reading,
+            max_upload_size: crate::CONFIG.read().unwrap().daemon_max_upload_size,
         }
     }
 
-    pub async fn run(mut self) -> Result<(), crate::error::StoreError> {
+    /// Overrides `daemon-max-upload-size` for this connection
+    pub fn set_max_upload_size(&mut self, max: u64) {
+        self.max_upload_size = max;
+    }
+
+    /// Close the connection between two operations once `shutdown` turns true
+    pub fn set_shutdown(&mut self, shutdown: tokio::sync::watch::Receiver<bool>) {
+        self.shutdown = Some(shutdown);
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Record that the current op changes `path`
+    fn affects(&mut self, path: &crate::store::StorePath) {
+        self.affected.push(self.store.print_store_path(path));
+    }
+
+    /// Minor version of the negotiated protocol
+    fn minor(&self) -> u16 {
+        get_protocol_minor(self.version)
+    }
+
+    /// Maximum size of a NAR the client may upload in one op, `None` if unlimited
+    fn upload_limit(&self) -> Option<u64> {
+        if self.trusted {
+            return None;
+        }
+        match self.max_upload_size {
+            0 => None,
+            v => Some(v),
+        }
+    }
+
+    /// Take one of the build slots of the user, `None` if builds are unlimited
+    fn build_slot(&self) -> Result<Option<limits::BuildSlot>, StoreError> {
+        let (max, dir) = {
+            let config = crate::CONFIG.read().unwrap();
+            (
+                config.daemon_max_builds_per_user,
+                format!("{}/daemon-builds", config.nix_state_dir),
+            )
+        };
+        if max == 0 {
+            return Ok(None);
+        }
+
+        match limits::BuildSlot::acquire(std::path::Path::new(&dir), self.uid, max)? {
+            Some(v) => Ok(Some(v)),
+            None => Err(StoreError::TooManyBuilds {
+                user: self.u_name.clone(),
+                max,
+            }),
+        }
+    }
+
+    /// Open the log of the op once all of its arguments are read
+    async fn start_work(&mut self) -> EmptyResult {
+        self.state = OpState::Working;
         self.con.start_work().await?;
+        Ok(())
+    }
+
+    /// Close the log of the op, everything written afterwards is its reply
+    async fn stop_work(&mut self) -> EmptyResult {
+        self.state = OpState::Done;
+        self.con.stop_work(WORKDONE).await?;
+        Ok(())
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        match &self.shutdown {
+            Some(v) => *v.borrow(),
+            None => false,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<(), crate::error::StoreError> {
+        self.start_work().await?;
 
         self.store
             .create_user(self.u_name.clone(), self.uid)
             .await?;
         //self.con.stop_work(WORKDONE).await?;
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
 
         loop {
             // daemon loop
-            let command = self.con.read_u64().await?;
+            if self.shutdown_requested() {
+                debug!("daemon is shutting down, closing connection");
+                return Ok(());
+            }
+
+            let command = match &mut self.shutdown {
+                Some(shutdown) => {
+                    tokio::select! {
+                        command = self.con.read_u64() => Some(command?),
+                        changed = shutdown.changed() => {
+                            if changed.is_err() {
+                                // the daemon dropped the sender, nobody can ask us to stop anymore
+                                self.shutdown = None;
+                            }
+                            None
+                        }
+                    }
+                }
+                None => Some(self.con.read_u64().await?),
+            };
+            let command = match command {
+                Some(v) => v,
+                None => continue,
+            };
 
             // let command = Command::from(command);
             let command = crate::store::protocol::WorkerOp::from(command as u32);
-            println!("command: {:?}", command);
-            self.perform_op(command).await?;
+            debug!("command: {:?}", command);
+            for observer in &self.observers {
+                observer.op_started(command);
+            }
+            let started = std::time::Instant::now();
+            self.affected.clear();
+            self.state = OpState::Reading;
+            let result = self.perform_op(command).await;
+            let report = OpReport {
+                op: command,
+                duration: started.elapsed(),
+                error: result.as_ref().err(),
+                paths: &self.affected,
+            };
+            for observer in &self.observers {
+                observer.op_finished(&report);
+            }
+            if let Err(e) = result {
+                debug!("{:?} failed: {}", command, e);
+                let recoverable = match self.state {
+                    // ops read all of their arguments before checking them
+                    OpState::Reading => !e.is_stream_error(),
+                    // the stream is in sync, unless an upload was cut off at the read limit
+                    OpState::Working => self.con.can_send(),
+                    OpState::Done => {
+                        // the client is reading the reply, an error would be taken as part of it
+                        debug!(
+                            "{:?} failed after sending STDERR_LAST, closing connection",
+                            command
+                        );
+                        return Err(e);
+                    }
+                };
+                if let Err(e) = self.send_error(&e).await {
+                    debug!("could not send error to client: {}", e);
+                    return Err(e);
+                }
+                if !recoverable {
+                    return Err(e);
+                }
+            }
         }
 
         //Ok(())
@@ -112,25 +360,49 @@ impl Connection {
         use crate::store::protocol::WorkerOp;
 
         match command {
-            WorkerOp::WopInvalidRequest => Ok(()),
+            WorkerOp::WopInvalidRequest => Err(StoreError::InvalidOperation {}),
             WorkerOp::WopSetOptions => self.set_options().await,
             WorkerOp::WopQueryPathInfo => self.query_path_info().await,
             WorkerOp::WopIsValidPath => self.is_valid_path().await,
+            WorkerOp::WopQueryReferrers => self.query_referrers().await,
+            WorkerOp::WopQueryValidPaths => self.query_valid_paths().await,
+            WorkerOp::WopQueryAllValidPaths => self.query_all_valid_paths().await,
+            WorkerOp::WopQueryPathFromHashPart => self.query_path_from_hash_part().await,
+            WorkerOp::WopQueryDerivationOutputs => self.query_derivation_outputs().await,
+            WorkerOp::WopQueryDerivationOutputMap => self.query_derivation_output_map().await,
+            WorkerOp::WopQueryValidDerivers => self.query_valid_derivers().await,
             WorkerOp::WopAddTempRoot => self.add_temp_root().await,
             WorkerOp::WopAddIndirectRoot => self.add_indirect_root().await,
             WorkerOp::WopSyncWithGC => self.sync_with_gc().await,
+            WorkerOp::WopCollectGarbage => self.collect_garbage().await,
             WorkerOp::WopAddToStoreNar => self.add_to_store_nar().await,
             WorkerOp::WopAddToStore => self.add_to_store().await,
             WorkerOp::WopEnsurePath => self.ensure_path().await,
             WorkerOp::WopAddTextToStore => self.add_text_to_store().await,
             WorkerOp::WopBuildPaths => self.build_paths().await,
-            _ => {
-                error!("not yet implemented");
-                Ok(())
-            }
+            WorkerOp::WopBuildDerivation => self.build_derivation().await,
+            WorkerOp::WopQueryMissing => self.query_missing().await,
+            WorkerOp::WopNarFromPath => self.nar_from_path().await,
+            WorkerOp::WopAddSignatures => self.add_signatures().await,
+            WorkerOp::WopVerifyStore => self.verify_store().await,
+            WorkerOp::WopOptimiseStore => self.optimise_store().await,
+            op => Err(StoreError::UnsupportedOperation {
+                op: format!("{:?}", op),
+            }),
         }
     }
 
+    /// Report `e` to the client as `STDERR_ERROR`
+    async fn send_error(&self, e: &StoreError) -> EmptyResult {
+        self.con
+            .stop_work(crate::source::WorkFinish::Error(
+                e.to_string(),
+                e.exit_status(),
+            ))
+            .await?;
+        Ok(())
+    }
+
     async fn set_options(&mut self) -> EmptyResult {
         let mut settings = ClientSettings::new();
 
@@ -148,20 +420,21 @@ impl Connection {
         settings.build_cores = self.con.read_u64().await? as u32;
         settings.use_substitutes = self.con.read_u64().await? != 0;
 
-        // TODO: check for client version >= 12
-        let n = self.con.read_u64().await?;
-        trace!("{} extra options", n);
-        for _i in 0..n {
-            let name = self.con.read_string().await?;
-            let value = self.con.read_string().await?;
-            settings.overrides.insert(name, Data::String(value));
-            warn!("set options not yet fully implemented");
+        if self.minor() >= 12 {
+            let n = self.con.read_u64().await?;
+            trace!("{} extra options", n);
+            for _i in 0..n {
+                let name = self.con.read_string().await?;
+                let value = self.con.read_string().await?;
+                settings.overrides.insert(name, Data::String(value));
+                warn!("set options not yet fully implemented");
+            }
         }
 
-        self.con.start_work().await?;
-        println!("settings: {:?}", settings);
+        self.start_work().await?;
+        trace!("settings: {:?}", settings);
         // FIXME: apply settings (when not recursive)
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
 
         Ok(())
     }
@@ -170,9 +443,15 @@ impl Connection {
         let path = self.con.read_string().await?;
         let path = self.store.parse_store_path(&path)?;
         debug!("queriying path info for {}", path);
-        self.con.start_work().await?;
+        self.start_work().await?;
         let info = self.store.query_path_info(&path).await;
-        self.con.stop_work(WORKDONE).await?;
+        if self.minor() < 17 {
+            // older clients have no way to express an invalid path, fail while the log is open
+            if let Err(e) = info {
+                return Err(e);
+            }
+        }
+        self.stop_work().await?;
 
         match info {
             Err(e) => {
@@ -184,7 +463,9 @@ impl Connection {
                 self.con.write_u64(0).await?;
             }
             Ok(v) => {
-                self.con.write_u64(1).await?;
+                if self.minor() >= 17 {
+                    self.con.write_u64(1).await?;
+                }
                 if let Some(v) = v.deriver {
                     self.con
                         .write_string(&self.store.print_store_path(&v))
@@ -208,12 +489,14 @@ impl Connection {
                     self.con.write_u64(0).await?;
                 }
 
-                self.con.write_bool(v.ultimate).await?;
-                self.con.write_strings(&v.sigs).await?;
-                if let Some(ca) = v.ca {
-                    self.con.write_string(&ca).await?;
-                } else {
-                    self.con.write_string("").await?;
+                if self.minor() >= 16 {
+                    self.con.write_bool(v.ultimate).await?;
+                    self.con.write_strings(&v.sigs).await?;
+                    if let Some(ca) = v.ca {
+                        self.con.write_string(&ca).await?;
+                    } else {
+                        self.con.write_string("").await?;
+                    }
                 }
             }
         }
@@ -227,24 +510,158 @@ impl Connection {
 
         debug!("checking if {} is a valid path", path);
 
-        self.con.start_work().await?;
+        self.start_work().await?;
         let valid = self.store.is_valid_path(&path).await?;
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
         self.con.write_bool(valid).await?;
 
         Ok(())
     }
 
+    async fn query_referrers(&mut self) -> EmptyResult {
+        let path = self.con.read_string().await?;
+        let path = self.store.parse_store_path(&path)?;
+
+        debug!("querying referrers of {}", path);
+
+        self.start_work().await?;
+        let referrers = self.store.query_referrers(&path).await?;
+        self.stop_work().await?;
+
+        let referrers: Vec<String> = referrers
+            .iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&referrers).await?;
+
+        Ok(())
+    }
+
+    async fn query_valid_paths(&mut self) -> EmptyResult {
+        let paths: Result<Vec<_>, StoreError> = self
+            .con
+            .read_strings()
+            .await?
+            .iter()
+            .map(|v| self.store.parse_store_path(v))
+            .collect();
+        let paths = paths?;
+        // clients send the substitute flag from minor 27 on, which is above PROTOCOL_VERSION,
+        // so substitutable paths are never asked for
+        const _: () = assert!(PROTOCOL_VERSION & 0xff < 27, "read the substitute flag");
+
+        debug!("checking {} paths for validity", paths.len());
+
+        self.start_work().await?;
+        let valid = self.store.query_valid_paths(&paths).await?;
+        self.stop_work().await?;
+
+        let valid: Vec<String> = valid
+            .iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&valid).await?;
+
+        Ok(())
+    }
+
+    async fn query_all_valid_paths(&mut self) -> EmptyResult {
+        self.start_work().await?;
+        let paths = self.store.query_all_valid_paths().await?;
+        self.stop_work().await?;
+
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&paths).await?;
+
+        Ok(())
+    }
+
+    async fn query_path_from_hash_part(&mut self) -> EmptyResult {
+        let hash_part = self.con.read_string().await?;
+
+        self.start_work().await?;
+        let path = self.store.query_path_from_hash_part(&hash_part).await?;
+        self.stop_work().await?;
+
+        match path {
+            Some(v) => {
+                self.con
+                    .write_string(&self.store.print_store_path(&v))
+                    .await?
+            }
+            None => self.con.write_string("").await?,
+        }
+
+        Ok(())
+    }
+
+    async fn query_derivation_outputs(&mut self) -> EmptyResult {
+        let path = self.con.read_string().await?;
+        let path = self.store.parse_store_path(&path)?;
+
+        self.start_work().await?;
+        let outputs = self.store.query_derivation_outputs(&path).await?;
+        self.stop_work().await?;
+
+        let outputs: Vec<String> = outputs
+            .iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&outputs).await?;
+
+        Ok(())
+    }
+
+    async fn query_derivation_output_map(&mut self) -> EmptyResult {
+        let path = self.con.read_string().await?;
+        let path = self.store.parse_store_path(&path)?;
+
+        self.start_work().await?;
+        let outputs = self.store.query_derivation_output_map(&path).await?;
+        self.stop_work().await?;
+
+        let mut outputs: Vec<(String, crate::store::StorePath)> = outputs.into_iter().collect();
+        outputs.sort();
+        self.con.write_u64(outputs.len() as u64).await?;
+        for (name, path) in &outputs {
+            self.con.write_string(name).await?;
+            self.con
+                .write_string(&self.store.print_store_path(path))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn query_valid_derivers(&mut self) -> EmptyResult {
+        let path = self.con.read_string().await?;
+        let path = self.store.parse_store_path(&path)?;
+
+        self.start_work().await?;
+        let derivers = self.store.query_valid_derivers(&path).await?;
+        self.stop_work().await?;
+
+        let derivers: Vec<String> = derivers
+            .iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&derivers).await?;
+
+        Ok(())
+    }
+
     async fn add_temp_root(&mut self) -> EmptyResult {
         let path = self.con.read_string().await?;
-        let path = std::path::PathBuf::from(&path);
+        let path = self.store.parse_store_path(&path)?;
 
-        debug!("adding temp root for {}", path.display());
+        debug!("adding temp root for {}", path);
 
-        self.con.start_work().await?;
-        warn!("implement add temp root"); // TODO: add temp root
-                                          //self.store.add_temp_root(&path).await?;
-        self.con.stop_work(WORKDONE).await?;
+        self.start_work().await?;
+        self.store.add_temp_root(&path).await?;
+        self.stop_work().await?;
         self.con.write_u64(1).await?;
 
         Ok(())
@@ -256,10 +673,10 @@ impl Connection {
 
         debug!("adding indirect root for {}", path.display());
 
-        self.con.start_work().await?;
+        self.start_work().await?;
         // TODO: store.add_indirect_root(&path).await?;
         warn!("implement indirect root");
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
         self.con.write_u64(1).await?;
 
         Ok(())
@@ -268,47 +685,97 @@ impl Connection {
     async fn sync_with_gc(&mut self) -> EmptyResult {
         debug!("syncing with gc");
 
-        self.con.start_work().await?;
+        self.start_work().await?;
         // TODO: store.add_indirect_root(&path).await?;
         warn!("implement gc sync");
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
         self.con.write_u64(1).await?;
 
         Ok(())
     }
 
+    async fn collect_garbage(&mut self) -> EmptyResult {
+        use std::convert::TryFrom;
+
+        let action = self.con.read_u64().await?;
+        let paths = self.con.read_strings().await?;
+        let ignore_liveness = self.con.read_u64().await? != 0;
+        let max_freed = self.con.read_u64().await?;
+        // obsolete fields
+        for _ in 0..3 {
+            self.con.read_u64().await?;
+        }
+
+        let action = crate::gc::GCAction::try_from(action)?;
+        let mut options = crate::gc::GCOptions::new(action);
+        for v in paths {
+            options
+                .paths_to_delete
+                .push(self.store.parse_store_path(&v)?);
+        }
+        options.max_freed = max_freed;
+
+        self.start_work().await?;
+        if ignore_liveness {
+            return Err(StoreError::NotPrivileged {
+                action: "ignore liveness".to_string(),
+            });
+        }
+        let results = self.store.collect_garbage(&options).await?;
+        if action.deletes() {
+            for v in &results.paths {
+                self.affects(v);
+            }
+        }
+        self.stop_work().await?;
+
+        let paths: Vec<String> = results
+            .paths
+            .iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&paths).await?;
+        self.con.write_u64(results.bytes_freed).await?;
+        self.con.write_u64(0).await?; // obsolete
+
+        Ok(())
+    }
+
     async fn add_to_store_nar(&mut self) -> EmptyResult {
         let path = self.con.read_string().await?;
+        let deriver = self.con.read_string().await?;
+        let nar_hash = self.con.read_string().await?;
+        let references = self.con.read_strings().await?;
+        let registration_time = self.con.read_u64().await?;
+        let nar_size = self.con.read_u64().await?;
+        let ultimate = self.con.read_u64().await? != 0;
+        let sigs = self.con.read_strings().await?;
+        let ca = self.con.read_string().await?; // TODO: better type
+        let repair = self.con.read_u64().await? != 0;
+        let mut dont_check_sigs = self.con.read_u64().await? != 0;
+
         //let path = std::path::PathBuf::from(&path);
         let path = self.store.parse_store_path(&path)?;
         //let mut path = super::store::ValidPathInfo::from(path);
         let mut path = super::store::ValidPathInfo::new(path);
-
-        let deriver = self.con.read_string().await?;
-        let deriver = self.store.parse_store_path(&deriver).ok();
-        path.deriver = deriver;
+        path.deriver = self.store.parse_store_path(&deriver).ok();
 
         debug!("add {} to store", path);
+        self.affects(&path.path);
 
         //path.nar_hash = super::store::Hash::sha256(self.con.read_string().await?);
-        path.nar_hash = super::store::Hash::from_sha256(&self.con.read_string().await?)?;
-        // read references
-        let store = &self.store;
-        let references = self.con.read_strings().await?;
-        let references: crate::store::path::StorePaths = references
-            .into_iter()
-            .map(move |v| store.parse_store_path(&v).unwrap())
+        path.nar_hash = super::store::Hash::from_sha256(&nar_hash)?;
+        let references: Result<crate::store::path::StorePaths, StoreError> = references
+            .iter()
+            .map(|v| self.store.parse_store_path(v))
             .collect();
-        path.references = references;
-        path.registration_time =
-            chrono::NaiveDateTime::from_timestamp(self.con.read_u64().await? as i64, 0);
-        path.nar_size = Some(self.con.read_u64().await?);
-        path.ultimate = self.con.read_u64().await? != 0;
-        path.sigs = self.con.read_strings().await?;
-        path.ca = Some(self.con.read_string().await?); // TODO: better type
+        path.references = references?;
+        path.registration_time = chrono::NaiveDateTime::from_timestamp(registration_time as i64, 0);
+        path.nar_size = Some(nar_size);
+        path.ultimate = ultimate;
+        path.sigs = sigs;
+        path.ca = if ca.is_empty() { None } else { Some(ca) };
 
-        let repair = self.con.read_u64().await? != 0;
-        let mut dont_check_sigs = self.con.read_u64().await? != 0;
         if !self.trusted && dont_check_sigs {
             dont_check_sigs = false;
         }
@@ -316,12 +783,30 @@ impl Connection {
             path.ultimate = false;
         }
 
-        self.con.start_work().await?;
+        let limit = self.upload_limit();
+        if let Some(max) = limit {
+            // older clients already send the NAR, the read limit stops it after the start
+            if nar_size > max && self.minor() >= 21 {
+                return Err(StoreError::UploadTooLarge {
+                    size: nar_size,
+                    max,
+                });
+            }
+        }
 
-        self.store
+        self.start_work().await?;
+
+        self.con.set_read_limit(limit);
+        // older clients send the NAR right after the arguments
+        self.con.set_tunnel(self.minor() >= 21);
+        let added = self
+            .store
             .add_to_store(path, /*source,*/ repair, !dont_check_sigs, &self.con)
-            .await?;
-        self.con.stop_work(WORKDONE).await?;
+            .await;
+        self.con.set_tunnel(false);
+        self.con.set_read_limit(None);
+        added?;
+        self.stop_work().await?;
 
         Ok(())
     }
@@ -331,9 +816,9 @@ impl Connection {
         let base_name = self.con.read_string().await?;
         let fixed = self.con.read_u64().await? != 0; // obsolete?
         let methode = self.con.read_u64().await?;
+        let mut s = self.con.read_string().await?;
         use std::convert::TryFrom;
         let mut methode = super::store::FileIngestionMethod::try_from(methode)?;
-        let mut s = self.con.read_string().await?;
 
         trace!("adding {} to store", base_name);
 
@@ -343,18 +828,22 @@ impl Connection {
             methode = super::store::FileIngestionMethod::Recursive;
         }
 
-        self.con.start_work().await?;
+        self.start_work().await?;
 
-        let hash = self.parse_dump(&base_name, methode).await?;
+        self.con.set_read_limit(self.upload_limit());
+        let hash = self.parse_dump(&base_name, methode).await;
+        self.con.set_read_limit(None);
+        let hash = hash?;
         // TODO: move path into store
         // How is the Hash calculated? from fixed output?
         warn!("get hash");
 
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
         // return store path to nix client
         warn!("return path");
         warn!("hash: {}", hash);
         // TODO: add to sql database
+        self.affects(&hash.path);
         let path = self.store.print_store_path(&hash.path);
         self.con.write_string(&path).await?; // TODO: rename to path
 
@@ -374,12 +863,13 @@ impl Connection {
             .collect();
         let refs = refs?;
 
-        self.con.start_work().await?;
+        self.start_work().await?;
         let path = self
             .store
             .add_text_to_store(&suffix, &s, &refs, false)
             .await?;
-        self.con.stop_work(WORKDONE).await?;
+        self.affects(&path.path);
+        self.stop_work().await?;
 
         let path = self.store.print_store_path(&path.path);
 
@@ -389,32 +879,195 @@ impl Connection {
     }
 
     async fn build_paths(&mut self) -> EmptyResult {
-        let mut drvs = self.con.read_strings().await?;
-        let drvs: Vec<crate::store::path::StorePathWithOutputs> = drvs
-            .drain(..)
-            .map(|v| self.store.parse_store_path_with_outputs(&v).unwrap())
+        let drvs = self.con.read_strings().await?;
+        let mode = if self.minor() >= 15 {
+            self.con.read_u64().await?
+        } else {
+            0 // bmNormal
+        };
+
+        let drvs: Result<Vec<crate::store::path::StorePathWithOutputs>, StoreError> = drvs
+            .iter()
+            .map(|v| self.store.parse_store_path_with_outputs(v))
             .collect();
-
-        let mode = self.con.read_u64().await?;
+        let drvs = drvs?;
+        for v in &drvs {
+            self.affects(&v.path);
+        }
         trace!("using mode: {}", mode);
 
-        self.con.start_work().await?;
+        self.start_work().await?;
+        let _slot = self.build_slot()?;
         warn!("build pathes");
         self.store.build_paths(drvs, mode as u8).await?;
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
+
+        self.con.write_u64(1).await?;
+
+        Ok(())
+    }
+
+    async fn build_derivation(&mut self) -> EmptyResult {
+        let drv_path = self.con.read_string().await?;
+        let drv =
+            crate::build::derivation::Derivation::from_wire(&self.con, &*self.store.box_clone())
+                .await;
+        let mode = self.con.read_u64().await?;
+        let drv_path = self.store.parse_store_path(&drv_path)?;
+        let drv = drv?;
+        use std::convert::TryFrom;
+        let mode = crate::store::BuildMode::try_from(mode)?;
+
+        self.start_work().await?;
+        // the derivation is not read from the store, so its outputs can not be trusted
+        if !self.trusted {
+            return Err(StoreError::NotPrivileged {
+                action: "build derivations".to_string(),
+            });
+        }
+        for v in drv.outputs.values() {
+            self.affects(&v.path);
+        }
+        let _slot = self.build_slot()?;
+        let result = self
+            .store
+            .build_derivation(
+                &drv_path,
+                &drv,
+                mode,
+                &crate::store::BuildOptions::from_config(),
+            )
+            .await?;
+        self.stop_work().await?;
+
+        self.con.write_u64(result.status as u64).await?;
+        self.con.write_string(&result.error_msg).await?;
+
+        Ok(())
+    }
+
+    async fn query_missing(&mut self) -> EmptyResult {
+        let paths: Result<Vec<_>, StoreError> = self
+            .con
+            .read_strings()
+            .await?
+            .iter()
+            .map(|v| self.store.parse_store_path_with_outputs(v))
+            .collect();
+        let paths = paths?;
+
+        self.start_work().await?;
+        let missing = self.store.query_missing(&paths).await?;
+        self.stop_work().await?;
+
+        for paths in &[
+            &missing.will_build,
+            &missing.will_substitute,
+            &missing.unknown,
+        ] {
+            let paths: Vec<String> = paths
+                .iter()
+                .map(|v| self.store.print_store_path(v))
+                .collect();
+            self.con.write_strings(&paths).await?;
+        }
+        self.con.write_u64(missing.download_size).await?;
+        self.con.write_u64(missing.nar_size).await?;
+
+        Ok(())
+    }
+
+    async fn nar_from_path(&mut self) -> EmptyResult {
+        let path = self.con.read_string().await?;
+        let path = self.store.parse_store_path(&path)?;
+
+        debug!("sending nar of {}", path);
+
+        self.start_work().await?;
+        // errors after the log ended can not be reported to the client, they close the connection
+        if !self.store.is_valid_path(&path).await? {
+            return Err(StoreError::InvalidPath {
+                path: self.store.print_store_path(&path),
+            });
+        }
+        self.stop_work().await?;
+
+        self.store.nar_from_path(&path, &self.con).await
+    }
+
+    async fn add_signatures(&mut self) -> EmptyResult {
+        let path = self.con.read_string().await?;
+        let sigs = self.con.read_strings().await?;
+        let path = self.store.parse_store_path(&path)?;
+
+        self.start_work().await?;
+        if !self.trusted {
+            return Err(StoreError::NotPrivileged {
+                action: "add signatures".to_string(),
+            });
+        }
+        self.affects(&path);
+        self.store.add_signatures(&path, &sigs).await?;
+        self.stop_work().await?;
+
+        self.con.write_u64(1).await?;
+
+        Ok(())
+    }
+
+    async fn optimise_store(&mut self) -> EmptyResult {
+        self.start_work().await?;
+        let stats = self.store.optimise_store().await?;
+        let msg = format!(
+            "{} bytes freed by hard-linking {} files",
+            stats.bytes_freed, stats.files_linked
+        );
+        self.con.log(&msg).await?;
+        self.stop_work().await?;
 
         self.con.write_u64(1).await?;
 
         Ok(())
     }
 
+    async fn verify_store(&mut self) -> EmptyResult {
+        let check_contents = self.con.read_u64().await? != 0;
+        let repair = self.con.read_u64().await? != 0;
+
+        self.start_work().await?;
+        if !self.trusted {
+            return Err(StoreError::NotPrivileged {
+                action: "verify the store".to_string(),
+            });
+        }
+        let report = self.store.verify_store(check_contents, repair).await?;
+        for v in &report.missing {
+            let msg = format!("path '{}' disappeared", self.store.print_store_path(v));
+            self.con.log(&msg).await?;
+        }
+        for v in &report.modified {
+            let msg = format!("path '{}' was modified", self.store.print_store_path(v));
+            self.con.log(&msg).await?;
+        }
+        for v in &report.invalidated {
+            self.affects(v);
+            let msg = format!("invalidated path '{}'", self.store.print_store_path(v));
+            self.con.log(&msg).await?;
+        }
+        self.stop_work().await?;
+
+        self.con.write_bool(report.errors()).await?;
+
+        Ok(())
+    }
+
     async fn ensure_path(&mut self) -> EmptyResult {
         let path = self.con.read_string().await?;
         trace!("ensure path {}", path);
 
-        self.con.start_work().await?;
+        self.start_work().await?;
         //self.store.ensure_path(path).await?; // TODO: implement
-        self.con.stop_work(WORKDONE).await?;
+        self.stop_work().await?;
 
         self.con.write_u64(1).await?;
         Ok(())
@@ -443,7 +1096,7 @@ impl Connection {
         self.con.set_hasher()?;
         let parser =
             crate::archive::NarParser::new(&extract_file, &self.con, self.store.box_clone_write());
-        let parser = parser.parse().await.unwrap();
+        parser.parse().await?;
         let parser = self.con.pop_hasher()?;
 
         let hash_compressed = parser.hash.clone();
@@ -476,3 +1129,203 @@ impl Drop for Connection {
         // TODOD: or send close here? but we don't have the last error
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{handshake, PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2};
+    use crate::error::StoreError;
+    use crate::source::test::Connection;
+    use crate::source::{AsyncRead, STDERR};
+    use crate::store::local_store::{test, LocalStore};
+    use crate::store::Store;
+    use std::sync::Arc;
+
+    fn input(v: &[u64]) -> Vec<u8> {
+        v.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+    }
+
+    fn string(v: &str) -> Vec<u8> {
+        let mut buf = input(&[v.len() as u64]);
+        buf.extend_from_slice(v.as_bytes());
+        buf.resize(buf.len() + (8 - v.len() % 8) % 8, 0);
+        buf
+    }
+
+    /// Run a worker connection over a socket pair, the client sends `input` and hangs up.
+    /// Returns how the connection ended and everything the daemon wrote.
+    async fn worker(
+        store: &Arc<LocalStore>,
+        trusted: bool,
+        version: u16,
+        input: Vec<u8>,
+    ) -> (Result<(), StoreError>, Vec<u8>) {
+        worker_with(store, trusted, version, input, |_| ()).await
+    }
+
+    /// Like `worker`, `configure` can change the connection before it runs
+    async fn worker_with(
+        store: &Arc<LocalStore>,
+        trusted: bool,
+        version: u16,
+        input: Vec<u8>,
+        configure: impl FnOnce(&mut super::Connection),
+    ) -> (Result<(), StoreError>, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut client, server) = tokio::net::UnixStream::pair().unwrap();
+        let uid = unsafe { libc::getuid() };
+        let mut connection = super::Connection::new(
+            trusted,
+            version,
+            crate::source::Connection::new(server),
+            Box::new(store.clone()),
+            uid,
+            "test".to_string(),
+        );
+        configure(&mut connection);
+
+        let client = async move {
+            client.write_all(&input).await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut output = Vec::new();
+            // the daemon resets the connection if it hangs up before reading all of the input
+            let _ = client.read_to_end(&mut output).await;
+            output
+        };
+        futures::join!(connection.run(), client)
+    }
+
+    #[tokio::test]
+    async fn invalid_argument() {
+        let store = test::open_test_store("worker-invalid-argument").await;
+        let path = format!(
+            "{}/ffffffffffffffffffffffffffffffff-foo",
+            store.get_store_dir().unwrap()
+        );
+
+        let mut request = input(&[1]);
+        request.extend(string("/foo/bar"));
+        request.extend(input(&[1]));
+        request.extend(string(&path));
+        let (result, output) = worker(&store, true, PROTOCOL_VERSION, request).await;
+        // the client hung up
+        assert!(result.is_err());
+
+        // the session survives the error and answers the second op
+        let error = store.parse_store_path("/foo/bar").unwrap_err();
+        let mut expected = input(&[STDERR::LAST as u64, STDERR::ERROR as u64]);
+        expected.extend(string(&error.to_string()));
+        expected.extend(input(&[1, STDERR::LAST as u64, 0]));
+        assert_eq!(output, expected);
+
+        test::remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn error_after_reply() {
+        let store = test::open_test_store("worker-error-after-reply").await;
+        // valid in the database, but missing on disk
+        let path = test::insert_path(&store, "ffffffffffffffffffffffffffffffff-foo", &[]);
+
+        let mut request = input(&[38]);
+        request.extend(string(&store.print_store_path(&path)));
+        request.extend(input(&[1]));
+        request.extend(string(&store.print_store_path(&path)));
+        let (result, output) = worker(&store, true, PROTOCOL_VERSION, request).await;
+
+        // the dump fails after its header, no error follows and the second op is never answered
+        assert!(result.is_err());
+        let mut expected = input(&[STDERR::LAST as u64, STDERR::LAST as u64]);
+        expected.extend(string("nix-archive-1"));
+        assert_eq!(output, expected);
+
+        test::remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn add_to_store_nar_too_large() {
+        let store = test::open_test_store("worker-upload-limit").await;
+        let path = format!(
+            "{}/ffffffffffffffffffffffffffffffff-foo",
+            store.get_store_dir().unwrap()
+        );
+        // before minor 21 the NAR follows the arguments, and its size is a lie
+        let mut request = input(&[39]);
+        request.extend(string(&path));
+        request.extend(string(""));
+        request.extend(string(&"0".repeat(64)));
+        request.extend(input(&[0, 0, 16, 0, 0]));
+        request.extend(string(""));
+        request.extend(input(&[0, 0]));
+        request.extend(crate::archive::dump_data(&[0; 1024]));
+        let (result, output) =
+            worker_with(&store, false, 0x114, request, |v| v.set_max_upload_size(64)).await;
+
+        // the rest of the NAR is never read, so the connection is closed after the error
+        assert!(result.is_err());
+        let expected = input(&[STDERR::LAST as u64, STDERR::ERROR as u64]);
+        assert!(output.starts_with(&expected), "{:?}", output);
+        let message = String::from_utf8_lossy(&output[expected.len() + 8..]);
+        assert!(
+            message.contains("exceeds the maximum of 64 bytes"),
+            "{}",
+            message
+        );
+        assert!(!std::path::Path::new(&path).exists());
+
+        test::remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn handshake_current() {
+        let con = Connection::new(
+            input(&[WORKER_MAGIC_1 as u64, PROTOCOL_VERSION as u64, 1, 3, 0]),
+            false,
+        );
+        let version = handshake(&con).await.unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        // all fields are consumed
+        assert_eq!(con.reader.lock().unwrap().position(), 5 * 8);
+
+        let writer = con.writer.lock().unwrap();
+        assert_eq!(
+            writer.get_ref(),
+            &input(&[WORKER_MAGIC_2 as u64, PROTOCOL_VERSION as u64])
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_old_client() {
+        // 1.10 knows neither CPU affinity nor reserveSpace
+        let con = Connection::new(input(&[WORKER_MAGIC_1 as u64, 0x10a, 42]), false);
+        assert_eq!(handshake(&con).await.unwrap(), 0x10a);
+        assert_eq!(con.read_u64().await.unwrap(), 42);
+
+        // 1.13 sends reserveSpace but no CPU affinity
+        let con = Connection::new(input(&[WORKER_MAGIC_1 as u64, 0x10d, 0, 42]), false);
+        assert_eq!(handshake(&con).await.unwrap(), 0x10d);
+        assert_eq!(con.read_u64().await.unwrap(), 42);
+
+        // CPU affinity is only followed by the cpu if it is set
+        let con = Connection::new(input(&[WORKER_MAGIC_1 as u64, 0x10e, 0, 0, 42]), false);
+        assert_eq!(handshake(&con).await.unwrap(), 0x10e);
+        assert_eq!(con.read_u64().await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn handshake_newer_client() {
+        let con = Connection::new(input(&[WORKER_MAGIC_1 as u64, 0x120, 0, 0]), false);
+        assert_eq!(handshake(&con).await.unwrap(), PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn handshake_invalid() {
+        let con = Connection::new(input(&[42]), false);
+        assert!(handshake(&con).await.is_err());
+
+        let con = Connection::new(input(&[WORKER_MAGIC_1 as u64, 0x109]), false);
+        assert!(handshake(&con).await.is_err());
+
+        let con = Connection::new(input(&[WORKER_MAGIC_1 as u64, 0x215]), false);
+        assert!(handshake(&con).await.is_err());
+    }
+}
@@ -0,0 +1,738 @@
+//! Server side of the legacy `nix-store --serve` protocol, used by remote builders and
+//! `nix-copy-closure`. Unlike the worker protocol there is no stderr channel, so every
+//! error ends the session.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+use log::*;
+
+use futures::future::LocalFutureObj;
+
+use crate::error::StoreError;
+use crate::source::{AsyncRead, AsyncWrite};
+use crate::store::{BuildOptions, BuildStore, Hash, StorePath, ValidPathInfo};
+
+type EmptyResult = Result<(), StoreError>;
+
+pub const SERVE_MAGIC_1: u32 = 0x390c9deb;
+pub const SERVE_MAGIC_2: u32 = 0x5452eecb;
+/// cmdAddToStoreNar of version 0x205 is not implemented, so clients do not use it
+pub const SERVE_PROTOCOL_VERSION: u16 = 0x204;
+
+/// Marks the metadata following a NAR in the output of `nix-store --export`
+pub const EXPORT_MAGIC: u32 = 0x4558494e;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServeCommand {
+    CmdQueryValidPaths = 1,
+    CmdQueryPathInfos = 2,
+    CmdDumpStorePath = 3,
+    CmdImportPaths = 4,
+    CmdExportPaths = 5,
+    CmdBuildPaths = 6,
+    CmdQueryClosure = 7,
+    CmdBuildDerivation = 8,
+    CmdAddToStoreNar = 9,
+    CmdInvalid = 0,
+}
+
+impl From<u64> for ServeCommand {
+    fn from(num: u64) -> Self {
+        use ServeCommand::*;
+        match num {
+            1 => CmdQueryValidPaths,
+            2 => CmdQueryPathInfos,
+            3 => CmdDumpStorePath,
+            4 => CmdImportPaths,
+            5 => CmdExportPaths,
+            6 => CmdBuildPaths,
+            7 => CmdQueryClosure,
+            8 => CmdBuildDerivation,
+            9 => CmdAddToStoreNar,
+            _ => CmdInvalid,
+        }
+    }
+}
+
+/// Hashes everything read or written through it, for NARs which do not go through
+/// the hasher of `source::Connection`
+struct Hashing<'a, C> {
+    inner: &'a C,
+    hasher: Mutex<(usize, ring::digest::Context)>,
+}
+
+impl<'a, C> Hashing<'a, C> {
+    fn new(inner: &'a C) -> Self {
+        Self {
+            inner,
+            hasher: Mutex::new((0, ring::digest::Context::new(&ring::digest::SHA256))),
+        }
+    }
+
+    fn update(&self, buf: &[u8]) {
+        let mut hasher = self.hasher.lock().unwrap();
+        hasher.0 += buf.len();
+        hasher.1.update(buf);
+    }
+
+    /// Returns the hash and the number of bytes passed through
+    fn finish(self) -> Result<(Hash, usize), StoreError> {
+        let (size, hasher) = self.hasher.into_inner().unwrap();
+        Ok((Hash::from_sha256_vec(hasher.finish().as_ref())?, size))
+    }
+}
+
+impl<'a, C: AsyncRead> AsyncRead for Hashing<'a, C> {
+    fn read_exact<'b>(
+        &'b self,
+        buf: &'b mut [u8],
+        len: usize,
+    ) -> LocalFutureObj<'b, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move {
+            let size = self.inner.read_exact(buf, len).await?;
+            self.update(&buf[0..size]);
+            Ok(size)
+        }))
+    }
+}
+
+/// Drops everything written to it, to only hash a NAR
+struct Discard;
+
+impl AsyncWrite for Discard {
+    fn write<'a>(&'a self, buf: &'a [u8]) -> LocalFutureObj<'a, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move { Ok(buf.len()) }))
+    }
+}
+
+impl<'a, C: AsyncWrite> AsyncWrite for Hashing<'a, C> {
+    fn write<'b>(&'b self, buf: &'b [u8]) -> LocalFutureObj<'b, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move {
+            let size = self.inner.write(buf).await?;
+            self.update(&buf[0..size]);
+            Ok(size)
+        }))
+    }
+}
+
+pub struct ServeConnection<C> {
+    con: C,
+
+    store: Box<dyn BuildStore>,
+
+    /// Whether the client may change the store, `nix-store --serve --write`
+    write: bool,
+
+    /// Protocol version negotiated in the handshake
+    version: u16,
+}
+
+impl<C: AsyncRead + AsyncWrite> ServeConnection<C> {
+    pub fn new(con: C, store: Box<dyn BuildStore>, write: bool) -> Self {
+        Self {
+            con,
+            store,
+            write,
+            version: SERVE_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Minor version of the negotiated protocol
+    fn minor(&self) -> u16 {
+        super::get_protocol_minor(self.version)
+    }
+
+    async fn handshake(&mut self) -> EmptyResult {
+        let magic = self.con.read_u64().await?;
+        if magic != SERVE_MAGIC_1 as u64 {
+            return Err(StoreError::InvalidMagic {});
+        }
+
+        self.con.write_u64(SERVE_MAGIC_2 as u64).await?;
+        self.con.write_u64(SERVE_PROTOCOL_VERSION as u64).await?;
+
+        let client_version = self.con.read_u64().await?;
+        if client_version > u16::MAX as u64
+            || super::get_protocol_major(client_version as u16)
+                != super::get_protocol_major(SERVE_PROTOCOL_VERSION)
+        {
+            return Err(StoreError::UnsupportedVersion {
+                version: client_version,
+            });
+        }
+        self.version = std::cmp::min(client_version as u16, SERVE_PROTOCOL_VERSION);
+        trace!(
+            "serve client version {:#x}, using version {:#x}",
+            client_version,
+            self.version
+        );
+
+        Ok(())
+    }
+
+    /// Serve commands until the client closes the connection
+    pub async fn run(mut self) -> EmptyResult {
+        self.handshake().await?;
+
+        loop {
+            let command = match self.con.read_u64().await {
+                Ok(v) => ServeCommand::from(v),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    debug!("serve client closed the connection");
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            trace!("serve command: {:?}", command);
+
+            self.perform_command(command).await?;
+        }
+    }
+
+    async fn perform_command(&mut self, command: ServeCommand) -> EmptyResult {
+        use ServeCommand::*;
+
+        match command {
+            CmdQueryValidPaths => self.query_valid_paths().await,
+            CmdQueryPathInfos => self.query_path_infos().await,
+            CmdDumpStorePath => self.dump_store_path().await,
+            CmdImportPaths => self.import_paths().await,
+            CmdExportPaths => self.export_paths().await,
+            CmdBuildPaths => self.build_paths().await,
+            CmdQueryClosure => self.query_closure().await,
+            CmdBuildDerivation => self.build_derivation().await,
+            CmdInvalid => Err(StoreError::InvalidOperation {}),
+            command => Err(StoreError::UnsupportedOperation {
+                op: format!("{:?}", command),
+            }),
+        }
+    }
+
+    fn check_write(&self, command: ServeCommand) -> EmptyResult {
+        if !self.write {
+            return Err(StoreError::WriteNotAllowed {
+                op: format!("{:?}", command),
+            });
+        }
+        Ok(())
+    }
+
+    async fn read_store_paths(&self) -> Result<Vec<StorePath>, StoreError> {
+        self.con
+            .read_strings()
+            .await?
+            .iter()
+            .map(|v| self.store.parse_store_path(v))
+            .collect()
+    }
+
+    async fn write_store_paths<'a, I>(&self, paths: I) -> EmptyResult
+    where
+        I: IntoIterator<Item = &'a StorePath>,
+    {
+        let paths: Vec<String> = paths
+            .into_iter()
+            .map(|v| self.store.print_store_path(v))
+            .collect();
+        self.con.write_strings(&paths).await?;
+        Ok(())
+    }
+
+    async fn query_valid_paths(&mut self) -> EmptyResult {
+        let lock = self.con.read_u64().await? != 0;
+        let substitute = self.con.read_u64().await? != 0;
+        let paths = self.read_store_paths().await?;
+
+        if lock && self.write {
+            for v in &paths {
+                self.store.add_temp_root(v).await?;
+            }
+        }
+        if substitute {
+            debug!("substitution is not supported, only reporting valid paths");
+        }
+
+        let valid = self.store.query_valid_paths(&paths).await?;
+        self.write_store_paths(&valid).await
+    }
+
+    async fn query_path_infos(&mut self) -> EmptyResult {
+        let paths = self.read_store_paths().await?;
+
+        for v in &paths {
+            if !self.store.is_valid_path(v).await? {
+                continue;
+            }
+            let info = self.store.query_path_info(v).await?;
+
+            self.con
+                .write_string(&self.store.print_store_path(&info.path))
+                .await?;
+            match &info.deriver {
+                Some(v) => {
+                    self.con
+                        .write_string(&self.store.print_store_path(v))
+                        .await?
+                }
+                None => self.con.write_string("").await?,
+            }
+            self.write_store_paths(&info.references).await?;
+            // download size, paths are sent uncompressed
+            self.con.write_u64(info.nar_size.unwrap_or(0)).await?;
+            self.con.write_u64(info.nar_size.unwrap_or(0)).await?;
+            if self.minor() >= 4 {
+                self.con
+                    .write_string(&format!("sha256:{}", info.nar_hash))
+                    .await?;
+                self.con
+                    .write_string(info.ca.as_deref().unwrap_or(""))
+                    .await?;
+                self.con.write_strings(&info.sigs).await?;
+            }
+        }
+        self.con.write_string("").await?;
+
+        Ok(())
+    }
+
+    async fn dump_store_path(&mut self) -> EmptyResult {
+        let path = self
+            .store
+            .parse_store_path(&self.con.read_string().await?)?;
+        self.store.nar_from_path(&path, &self.con).await
+    }
+
+    /// Send `paths` in the format of `nix-store --export`, references first
+    async fn export_paths(&mut self) -> EmptyResult {
+        self.con.read_u64().await?; // obsolete: sign
+        let paths = self.read_store_paths().await?;
+
+        let mut references = HashMap::new();
+        for v in &paths {
+            references.insert(v.clone(), self.store.query_references(v).await?);
+        }
+        for v in crate::store::path::references_first(&paths, &references) {
+            self.con.write_u64(1).await?;
+            self.export_path(&v).await?;
+        }
+        self.con.write_u64(0).await?;
+
+        Ok(())
+    }
+
+    async fn export_path(&self, path: &StorePath) -> EmptyResult {
+        let info = self.store.query_path_info(path).await?;
+
+        // checked before sending, a corrupt NAR could not be taken back
+        let sink = Hashing::new(&Discard);
+        self.store.nar_from_path(path, &sink).await?;
+        if sink.finish()?.0 != info.nar_hash {
+            return Err(StoreError::HashMismatch { path: path.clone() });
+        }
+        self.store.nar_from_path(path, &self.con).await?;
+
+        self.con.write_u64(EXPORT_MAGIC as u64).await?;
+        self.con
+            .write_string(&self.store.print_store_path(path))
+            .await?;
+        self.write_store_paths(&info.references).await?;
+        match &info.deriver {
+            Some(v) => {
+                self.con
+                    .write_string(&self.store.print_store_path(v))
+                    .await?
+            }
+            None => self.con.write_string("").await?,
+        }
+        self.con.write_u64(0).await?; // no signature
+
+        Ok(())
+    }
+
+    async fn import_paths(&mut self) -> EmptyResult {
+        self.check_write(ServeCommand::CmdImportPaths)?;
+
+        loop {
+            match self.con.read_u64().await? {
+                0 => break,
+                1 => self.import_path().await?,
+                _ => {
+                    return Err(StoreError::BadArchive {
+                        msg: "input doesn't look like something created by 'nix-store --export'"
+                            .to_string(),
+                    })
+                }
+            }
+        }
+        self.con.write_u64(1).await?;
+
+        Ok(())
+    }
+
+    /// Import a single path in the format of `nix-store --export`, the NAR followed by its metadata
+    async fn import_path(&mut self) -> EmptyResult {
+        let store_dir = self.store.get_store_dir()?;
+        let extract_file = format!("{}/.temp/import-{}", store_dir, std::process::id());
+        let extract_path = std::path::Path::new(&extract_file);
+        if extract_path.is_dir() {
+            std::fs::remove_dir_all(extract_path)?;
+        } else if extract_path.exists() {
+            std::fs::remove_file(extract_path)?;
+        }
+        if let Some(v) = extract_path.parent() {
+            std::fs::create_dir_all(v)?;
+        }
+
+        let reader = Hashing::new(&self.con);
+        let parser =
+            crate::archive::NarParser::new(&extract_file, &reader, self.store.box_clone_write());
+        parser.parse().await?;
+        drop(parser);
+        let (nar_hash, nar_size) = reader.finish()?;
+
+        if self.con.read_u64().await? != EXPORT_MAGIC as u64 {
+            return Err(StoreError::BadArchive {
+                msg: "Nix archive cannot be imported; wrong format".to_string(),
+            });
+        }
+        let path = self
+            .store
+            .parse_store_path(&self.con.read_string().await?)?;
+        let references = self.read_store_paths().await?;
+        let deriver = self.con.read_string().await?;
+        if self.con.read_u64().await? == 1 {
+            self.con.read_string().await?; // obsolete: signature
+        }
+
+        let mut info = ValidPathInfo::now(path, nar_hash, nar_size as u64)?;
+        info.references = references;
+        if !deriver.is_empty() {
+            info.deriver = Some(self.store.parse_store_path(&deriver)?);
+        }
+
+        if self.store.is_valid_path(&info.path).await? {
+            debug!("{} is already valid", info.path);
+            if extract_path.is_dir() {
+                std::fs::remove_dir_all(extract_path)?;
+            } else {
+                std::fs::remove_file(extract_path)?;
+            }
+            return Ok(());
+        }
+
+        debug!("importing {}", info.path);
+        self.store.add_temp_root(&info.path).await?;
+        self.store.delete_path(&info.path).await?;
+        std::fs::rename(&extract_file, self.store.print_store_path(&info.path))?;
+        self.store.register_path(info).await?;
+
+        Ok(())
+    }
+
+    /// Build settings sent with every build command, they only apply to that command
+    async fn read_build_settings(&mut self) -> Result<BuildOptions, StoreError> {
+        let max_silent_time = self.con.read_u64().await?;
+        let timeout = self.con.read_u64().await?;
+        if self.minor() >= 2 {
+            self.con.read_u64().await?; // TODO: max log size
+        }
+        if self.minor() >= 3 {
+            self.con.read_u64().await?; // TODO: build repeat
+            self.con.read_u64().await?; // TODO: enforce determinism
+        }
+
+        Ok(BuildOptions {
+            max_silent_time,
+            timeout,
+        })
+    }
+
+    async fn build_paths(&mut self) -> EmptyResult {
+        self.check_write(ServeCommand::CmdBuildPaths)?;
+
+        let paths: Result<Vec<_>, StoreError> = self
+            .con
+            .read_strings()
+            .await?
+            .iter()
+            .map(|v| self.store.parse_store_path_with_outputs(v))
+            .collect();
+        let paths = paths?;
+        // build_paths does not run builders yet, so there is nothing to limit
+        let _options = self.read_build_settings().await?;
+
+        // build failures are reported to the client, the session goes on
+        match self.store.build_paths(paths, 0).await {
+            Ok(()) => self.con.write_u64(0).await?,
+            Err(e) => {
+                debug!("build failed: {}", e);
+                self.con.write_u64(e.exit_status() as u64).await?;
+                self.con.write_string(&e.to_string()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Used by hydra-queue-runner, the derivation is sent instead of being read from the store
+    async fn build_derivation(&mut self) -> EmptyResult {
+        self.check_write(ServeCommand::CmdBuildDerivation)?;
+
+        let drv_path = self.con.read_string().await?;
+        let drv_path = self.store.parse_store_path(&drv_path)?;
+        let drv =
+            crate::build::derivation::Derivation::from_wire(&self.con, &*self.store.box_clone())
+                .await?;
+        let options = self.read_build_settings().await?;
+
+        let result = self
+            .store
+            .build_derivation(&drv_path, &drv, crate::store::BuildMode::Normal, &options)
+            .await?;
+
+        self.con.write_u64(result.status as u64).await?;
+        self.con.write_string(&result.error_msg).await?;
+        if self.minor() >= 3 {
+            self.con.write_u64(result.times_built as u64).await?;
+            self.con.write_bool(result.is_non_deterministic).await?;
+            self.con.write_u64(result.start_time as u64).await?;
+            self.con.write_u64(result.stop_time as u64).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn query_closure(&mut self) -> EmptyResult {
+        let include_outputs = self.con.read_u64().await? != 0;
+        let paths = self.read_store_paths().await?;
+
+        let mut closure = BTreeSet::new();
+        let mut queue: VecDeque<StorePath> = paths.into_iter().collect();
+        while let Some(path) = queue.pop_front() {
+            if closure.contains(&path) {
+                continue;
+            }
+            let info = self.store.query_path_info(&path).await?;
+            queue.extend(info.references);
+            // outputs which were not built or were collected are skipped
+            if include_outputs && path.is_derivation() {
+                for v in self.store.query_derivation_outputs(&path).await? {
+                    if self.store.is_valid_path(&v).await? {
+                        queue.push_back(v);
+                    }
+                }
+            }
+            closure.insert(path);
+        }
+
+        self.write_store_paths(&closure).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ServeConnection, EXPORT_MAGIC, SERVE_MAGIC_1, SERVE_MAGIC_2, SERVE_PROTOCOL_VERSION,
+    };
+    use crate::source::test::Connection;
+    use crate::store::mock_store::MockStore;
+    use crate::store::{Hash, ReadStore, StorePath, ValidPathInfo};
+    use std::sync::Arc;
+
+    const FOO: &str = "/nix/store/ffffffffffffffffffffffffffffffff-foo";
+    const BAR: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bar";
+    const BAZ: &str = "/nix/store/zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-baz";
+    const DRV: &str = "/nix/store/cccccccccccccccccccccccccccccccc-out.drv";
+    const OUT: &str = "/nix/store/dddddddddddddddddddddddddddddddd-out";
+
+    /// Encodes integers and strings like the wire protocol
+    #[derive(Default)]
+    struct Wire(Vec<u8>);
+
+    impl Wire {
+        fn u64(mut self, v: u64) -> Self {
+            self.0.extend_from_slice(&v.to_le_bytes());
+            self
+        }
+
+        fn string(mut self, v: &str) -> Self {
+            self = self.u64(v.len() as u64);
+            self.0.extend_from_slice(v.as_bytes());
+            self.0.resize(self.0.len() + (8 - v.len() % 8) % 8, 0);
+            self
+        }
+
+        fn strings(mut self, v: &[&str]) -> Self {
+            self = self.u64(v.len() as u64);
+            for v in v {
+                self = self.string(v);
+            }
+            self
+        }
+
+        fn handshake() -> Self {
+            Self::default().u64(SERVE_MAGIC_1 as u64).u64(0x204)
+        }
+    }
+
+    fn store() -> Arc<MockStore> {
+        let store = Arc::new(MockStore::new());
+        let path = |v: &str| StorePath::new(&v["/nix/store/".len()..]).unwrap();
+
+        let mut foo = ValidPathInfo::new(path(FOO));
+        foo.nar_hash = Hash::from_sha256_vec(&[0; 32]).unwrap();
+        foo.nar_size = Some(120);
+        foo.references = vec![path(BAR)];
+        store.add_path_info(foo);
+
+        let mut bar = ValidPathInfo::new(path(BAR));
+        bar.nar_hash = Hash::from_sha256_vec(&[0; 32]).unwrap();
+        bar.nar_size = Some(80);
+        bar.sigs = vec!["cache:sig".to_string()];
+        store.add_path_info(bar);
+
+        store.add_path_info(ValidPathInfo::new(path(DRV)));
+        let mut out = ValidPathInfo::new(path(OUT));
+        out.deriver = Some(path(DRV));
+        out.references = vec![path(BAR)];
+        store.add_path_info(out);
+
+        store
+    }
+
+    async fn serve(input: Wire, write: bool) -> Vec<u8> {
+        let con = Connection::new(input.0, false);
+        let writer = con.writer.clone();
+        let connection = ServeConnection::new(con, Box::new(store()), write);
+        connection.run().await.unwrap();
+
+        let output = writer.lock().unwrap().get_ref().clone();
+        output
+    }
+
+    fn reply() -> Wire {
+        Wire::default()
+            .u64(SERVE_MAGIC_2 as u64)
+            .u64(SERVE_PROTOCOL_VERSION as u64)
+    }
+
+    #[tokio::test]
+    async fn query_valid_paths() {
+        let input = Wire::handshake().u64(1).u64(0).u64(0).strings(&[FOO, BAZ]);
+        assert_eq!(serve(input, false).await, reply().strings(&[FOO]).0);
+    }
+
+    #[tokio::test]
+    async fn query_path_infos() {
+        let input = Wire::handshake().u64(2).strings(&[BAR, BAZ]);
+        let hash = format!("sha256:{}", Hash::from_sha256_vec(&[0; 32]).unwrap());
+        let expected = reply()
+            .string(BAR)
+            .string("")
+            .strings(&[])
+            .u64(80)
+            .u64(80)
+            .string(&hash)
+            .string("")
+            .strings(&["cache:sig"])
+            .string("");
+        assert_eq!(serve(input, false).await, expected.0);
+    }
+
+    #[tokio::test]
+    async fn query_closure() {
+        let input = Wire::handshake().u64(7).u64(0).strings(&[FOO]);
+        // sorted by hash part
+        assert_eq!(serve(input, false).await, reply().strings(&[BAR, FOO]).0);
+
+        let input = Wire::handshake().u64(7).u64(0).strings(&[DRV]);
+        assert_eq!(serve(input, false).await, reply().strings(&[DRV]).0);
+        let input = Wire::handshake().u64(7).u64(1).strings(&[DRV]);
+        assert_eq!(
+            serve(input, false).await,
+            reply().strings(&[BAR, DRV, OUT]).0
+        );
+    }
+
+    #[tokio::test]
+    async fn export_modified() {
+        use crate::store::{Store, WriteStore};
+
+        let store = crate::store::local_store::test::open_test_store("serve-export").await;
+        let info = store
+            .add_text_to_store("foo", b"foo", &Vec::new(), false)
+            .await
+            .unwrap();
+        std::fs::remove_file(store.print_store_path(&info.path)).unwrap();
+        std::fs::write(store.print_store_path(&info.path), "bar").unwrap();
+
+        let input = Wire::handshake()
+            .u64(5)
+            .u64(0)
+            .strings(&[&store.print_store_path(&info.path)]);
+        let con = Connection::new(input.0, false);
+        let writer = con.writer.clone();
+        let connection = ServeConnection::new(con, Box::new(store.clone()), false);
+        assert!(connection.run().await.is_err());
+        // nothing of the NAR was sent
+        assert_eq!(writer.lock().unwrap().get_ref(), &reply().u64(1).0);
+
+        crate::store::local_store::test::remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn import_read_only() {
+        let con = Connection::new(Wire::handshake().u64(4).u64(0).0, false);
+        let connection = ServeConnection::new(con, Box::new(store()), false);
+        assert!(connection.run().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn import_with_references() {
+        let store = crate::store::local_store::test::open_test_store("serve-import").await;
+        let store_dir = store.get_store_dir();
+        let foo = format!("{}/{}", store_dir, &FOO["/nix/store/".len()..]);
+        let bar = format!("{}/{}", store_dir, &BAR["/nix/store/".len()..]);
+
+        let mut input = Wire::handshake().u64(4).u64(1);
+        input.0.extend(crate::archive::dump_data(b"bar"));
+        input = input
+            .u64(EXPORT_MAGIC as u64)
+            .string(&bar)
+            .strings(&[])
+            .string("");
+        input = input.u64(0).u64(1);
+        input.0.extend(crate::archive::dump_data(foo.as_bytes()));
+        input = input
+            .u64(EXPORT_MAGIC as u64)
+            .string(&foo)
+            .strings(&[&bar, &foo]);
+        input = input.string("").u64(0).u64(0);
+
+        let con = Connection::new(input.0, false);
+        let writer = con.writer.clone();
+        let connection = ServeConnection::new(con, Box::new(store.clone()), true);
+        connection.run().await.unwrap();
+        assert_eq!(writer.lock().unwrap().get_ref(), &reply().u64(1).0);
+
+        let path = |v: &str| StorePath::new(&v["/nix/store/".len()..]).unwrap();
+        let mut references = store.query_references(&path(FOO)).await.unwrap();
+        references.sort_by_key(|v| v.to_string());
+        assert_eq!(references, vec![path(BAR), path(FOO)]);
+        assert_eq!(
+            store.query_referrers(&path(BAR)).await.unwrap(),
+            vec![path(FOO)]
+        );
+
+        crate::store::local_store::test::remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn invalid_magic() {
+        let con = Connection::new(Wire::default().u64(0x6e697863).0, false);
+        let connection = ServeConnection::new(con, Box::new(store()), false);
+        assert!(connection.run().await.is_err());
+    }
+}
@@ -31,14 +31,62 @@ custom_error! {
         BadArchive{ msg: String } = "BadArchive: {msg}",
         NoBuildJobs{ jobs: usize } = "{jobs} derivations need to be built, but neither local builds ('--max-jobs') nor remote builds ('--builders') are enabled",
         InvalidHashPart{ path: String, hash_part: String } = "The path {path} does not have a valid hash part {hash_part}",
+        BadHashPart{ hash_part: String } = "invalid hash part '{hash_part}'",
+        InvalidPath{ path: String } = "path '{path}' is not valid",
         HashMismatch{ path: crate::store::StorePath } = "The Hash for {path} does not match",
         InvalidDerivation{ msg: String } = "InvalidDerivation: {msg}",
         //BadArchive{ source: NarError } = "BadArchive: {source}",
+        NarError{ error: Box<NarError> } = "BadArchive: {error}",
+        BuildError{ source: BuildError } = "BuildError: {source}",
         BadBase32 = "Bad base 32 structure",
+        InvalidMagic{} = "protocol mismatch, the worker magic is invalid",
+        ClientTooOld{ version: u16 } = "the Nix client version {version} is too old",
+        UnsupportedVersion{ version: u64 } = "unsupported protocol version {version}",
+        InvalidOperation{} = "invalid operation",
+        UnsupportedOperation{ op: String } = "operation {op} is not supported by this daemon",
+        TooManyBuilds{ user: String, max: usize } = "user {user} already runs the maximum of {max} concurrent builds",
+        WriteNotAllowed{ op: String } = "{op} is not allowed, the store is served read-only",
+        NotPrivileged{ action: String } = "you are not privileged to {action}",
+        UploadTooLarge{ size: u64, max: u64 } = "upload of {size} bytes exceeds the maximum of {max} bytes for untrusted users",
+        InvalidGcAction{ action: u64 } = "invalid garbage collector action {action}",
+        InvalidBuildMode{ mode: u64 } = "invalid build mode {mode}",
+        PathAlive{ path: String } = "cannot delete path '{path}' since it is still alive",
 
         Unimplemented{ msg: String } = "Unimplemented: {msg}",
 }
 
+impl StoreError {
+    /// Exit status reported to the client, following the exit codes of `nix-build`
+    pub fn exit_status(&self) -> usize {
+        match self {
+            StoreError::HashMismatch { .. } => 102,
+            _ => 1,
+        }
+    }
+
+    /// Errors of the connection itself, after them it is unknown where the next op starts
+    pub fn is_stream_error(&self) -> bool {
+        matches!(
+            self,
+            StoreError::Io { .. }
+                | StoreError::ConnectionError { .. }
+                | StoreError::StringToLong { .. }
+                | StoreError::NarError { .. }
+        )
+    }
+}
+
+impl From<NarError> for StoreError {
+    fn from(error: NarError) -> Self {
+        match error {
+            NarError::StoreError { source } => source,
+            error => StoreError::NarError {
+                error: Box::new(error),
+            },
+        }
+    }
+}
+
 custom_error! {
     pub ConnectionError
         Io{source: io::Error} = "IoError: {source}",
@@ -57,6 +105,7 @@ custom_error! {
         NotSorted{} = "NAR archive is not sorted",
         MissingName{} = "etry name is missing",
         InvalidSymlinkMarker{ marker: String } = "invalid target marker for symlink: '{marker}'",
+        UnsupportedFileType{ path: String } = "file '{path}' has an unsupported type",
         InvalidState{state: crate::archive::State } = "InvalidState: {state}", // TODO: add case info
 }
 
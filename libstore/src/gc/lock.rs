@@ -1,6 +1,7 @@
 use std::os::unix::io::{AsRawFd, RawFd};
 
 #[repr(i32)]
+#[derive(Debug, Clone, Copy)]
 pub enum LockType {
     Read = libc::LOCK_SH,
     Write = libc::LOCK_EX,
@@ -1 +1,278 @@
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::*;
+
+use crate::error::StoreError;
+use crate::store::path::StorePaths;
+
 pub mod lock;
+pub mod roots;
+
+/// What the garbage collector does with the paths it finds
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GCAction {
+    /// Return the paths reachable from the roots
+    ReturnLive = 0,
+    /// Return the paths not reachable from the roots
+    ReturnDead = 1,
+    DeleteDead = 2,
+    /// Delete `GCOptions::paths_to_delete`, fails if one of them is alive
+    DeleteSpecific = 3,
+}
+
+impl GCAction {
+    pub fn deletes(self) -> bool {
+        self == GCAction::DeleteDead || self == GCAction::DeleteSpecific
+    }
+}
+
+impl std::convert::TryFrom<u64> for GCAction {
+    type Error = StoreError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(GCAction::ReturnLive),
+            1 => Ok(GCAction::ReturnDead),
+            2 => Ok(GCAction::DeleteDead),
+            3 => Ok(GCAction::DeleteSpecific),
+            _ => Err(StoreError::InvalidGcAction { action: value }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GCOptions {
+    pub action: GCAction,
+    pub paths_to_delete: StorePaths,
+    /// Stop deleting once this many bytes are freed
+    pub max_freed: u64,
+}
+
+impl GCOptions {
+    pub fn new(action: GCAction) -> Self {
+        Self {
+            action,
+            paths_to_delete: Vec::new(),
+            max_freed: u64::MAX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GCResults {
+    /// Live or dead paths for the `Return*` actions, the deleted paths otherwise
+    pub paths: StorePaths,
+    pub bytes_freed: u64,
+}
+
+/// Take the lock of the garbage collector, it is held until the file is dropped.
+/// The collector holds it exclusively, writers share it while they add temp roots.
+/// Waiting does not block the thread, other connections keep being served.
+pub async fn lock_gc(state_dir: &str, lock_type: lock::LockType) -> std::io::Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(format!("{}/gc.lock", state_dir.trim_end_matches('/')))?;
+    if !lock::lock_file(&file, lock_type, false)? {
+        match lock_type {
+            lock::LockType::Read => info!("waiting for the garbage collector to finish..."),
+            _ => info!("waiting for the big garbage collector lock..."),
+        }
+        while !lock::lock_file(&file, lock_type, false)? {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    }
+    Ok(file)
+}
+
+/// Disk space freed by deleting `path`, files with other hard links do not count
+pub fn path_size(path: &Path) -> std::io::Result<u64> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !meta.is_dir() {
+        return Ok(if meta.nlink() == 1 {
+            meta.blocks() * 512
+        } else {
+            0
+        });
+    }
+
+    let mut size = 0;
+    for v in std::fs::read_dir(path)? {
+        size += path_size(&v?.path())?;
+    }
+    Ok(size)
+}
+
+/// Delete the tree at `path`, directories in the store are read-only
+pub fn delete_tree(path: &Path) -> std::io::Result<()> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !meta.is_dir() {
+        return std::fs::remove_file(path);
+    }
+
+    let mode = meta.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode | 0o700))?;
+    }
+    for v in std::fs::read_dir(path)? {
+        delete_tree(&v?.path())?;
+    }
+    std::fs::remove_dir(path)
+}
+
+/// Remove the entries of `.links` which are not linked into the store anymore,
+/// returns the bytes freed
+pub fn remove_unused_links(store_dir: &str) -> std::io::Result<u64> {
+    let dir = format!("{}/.links", store_dir);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for v in entries {
+        let v = v?;
+        let meta = v.metadata()?;
+        if meta.nlink() != 1 {
+            continue;
+        }
+        match std::fs::remove_file(v.path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        removed += 1;
+        freed += meta.blocks() * 512;
+    }
+    debug!("removed {} unused links, freeing {} bytes", removed, freed);
+
+    Ok(freed)
+}
+
+/// Temporary roots of one connection. They are kept in a file which is read-locked while the
+/// connection is open, so the collector can tell them from files left behind by a crash.
+/// The file is removed on drop.
+#[derive(Debug)]
+pub struct TempRoots {
+    path: String,
+    file: std::fs::File,
+}
+
+impl TempRoots {
+    /// Create a new, locked temp roots file in `state_dir`
+    pub fn new(state_dir: &str) -> std::io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        let dir = format!("{}/temproots", state_dir.trim_end_matches('/'));
+        std::fs::create_dir_all(&dir)?;
+        loop {
+            let path = format!(
+                "{}/{}-{}",
+                dir,
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let file = match std::fs::OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(&path)
+            {
+                Ok(v) => v,
+                // left behind by an earlier process with the same pid
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            lock::lock_file(&file, lock::LockType::Read, true)?;
+            // the collector removes files it can lock, it may have been faster than us
+            if file.metadata()?.nlink() == 0 {
+                continue;
+            }
+            trace!("created temp roots file {}", path);
+            return Ok(Self { path, file });
+        }
+    }
+
+    /// Keep `path` alive until the roots are dropped
+    pub fn add(&mut self, path: &str) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let mut entry = path.as_bytes().to_vec();
+        entry.push(0);
+        // a single write, so the collector never reads half of an entry
+        self.file.write_all(&entry)
+    }
+}
+
+impl Drop for TempRoots {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("could not remove temp roots file {}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn delete_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("nix-test-gc-delete-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), vec![1; 10_000]).unwrap();
+        assert!(super::path_size(&dir).unwrap() >= 10_000);
+
+        // a file linked from somewhere else is not freed
+        std::fs::hard_link(dir.join("sub/file"), dir.join("link")).unwrap();
+        assert_eq!(super::path_size(&dir.join("sub")).unwrap(), 0);
+
+        std::fs::set_permissions(dir.join("sub"), std::fs::Permissions::from_mode(0o555)).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+        super::delete_tree(&dir).unwrap();
+        assert!(!dir.exists());
+        super::delete_tree(&dir).unwrap();
+    }
+
+    #[test]
+    fn temp_roots() {
+        let state_dir = format!("/tmp/nix-test-temp-roots-{}", std::process::id());
+        let mut roots = super::TempRoots::new(&state_dir).unwrap();
+        let other = super::TempRoots::new(&state_dir).unwrap();
+        assert_ne!(roots.path, other.path);
+
+        roots
+            .add("/nix/store/ffffffffffffffffffffffffffffffff-x")
+            .unwrap();
+        roots
+            .add("/nix/store/ffffffffffffffffffffffffffffffff-y")
+            .unwrap();
+        assert_eq!(
+            std::fs::read(&roots.path).unwrap(),
+            b"/nix/store/ffffffffffffffffffffffffffffffff-x\0/nix/store/ffffffffffffffffffffffffffffffff-y\0".to_vec()
+        );
+
+        // the collector can not lock the file of a live connection
+        let file = std::fs::File::open(&roots.path).unwrap();
+        assert!(!super::lock::lock_file(&file, super::lock::LockType::Write, false).unwrap());
+
+        let path = roots.path.clone();
+        drop(roots);
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(std::path::Path::new(&other.path).exists());
+        drop(other);
+        std::fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! Roots of the garbage collector: links below `gcroots` and `profiles`, the temporary roots
+//! of running processes and store paths used by running processes.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::*;
+
+use crate::store::path::{is_valid_hash_part, HASHLEN};
+use crate::store::StorePath;
+
+/// Store paths which must not be collected, with the links or files keeping them alive
+pub type Roots = HashMap<StorePath, Vec<String>>;
+
+fn add_root(roots: &mut Roots, path: StorePath, reason: String) {
+    trace!("found root {} in {}", path, reason);
+    roots.entry(path).or_default().push(reason);
+}
+
+/// `name` as a store path, without the warnings of `StorePath::new` for random strings
+fn parse_base_name(name: &str) -> Option<StorePath> {
+    let hash_part = name.get(..HASHLEN as usize)?;
+    if !is_valid_hash_part(hash_part) || name.as_bytes().get(HASHLEN as usize) != Some(&b'-') {
+        return None;
+    }
+    StorePath::new(name).ok()
+}
+
+/// The store path containing `path`, if it is inside `store_dir`
+pub fn to_store_path(store_dir: &str, path: &str) -> Option<StorePath> {
+    let rest = path.strip_prefix(store_dir)?.strip_prefix('/')?;
+    parse_base_name(rest.split('/').next()?)
+}
+
+/// Store paths mentioned anywhere in `text`
+fn scan_store_paths(store_dir: &str, text: &str) -> Vec<StorePath> {
+    let prefix = format!("{}/", store_dir);
+    let mut paths = Vec::new();
+    let mut rest = text;
+    while let Some(i) = rest.find(&prefix) {
+        rest = &rest[i + prefix.len()..];
+        let end = rest
+            .find(|v: char| !(v.is_ascii_alphanumeric() || "+-._?=".contains(v)))
+            .unwrap_or(rest.len());
+        paths.extend(parse_base_name(&rest[..end]));
+    }
+    paths
+}
+
+/// Roots below `gcroots` and `profiles` in `state_dir`
+pub fn find_roots(state_dir: &str, store_dir: &str) -> std::io::Result<Roots> {
+    let state_dir = state_dir.trim_end_matches('/');
+    let auto_dir = format!("{}/gcroots/auto", state_dir);
+    let mut roots = Roots::new();
+    for v in &["gcroots", "profiles"] {
+        let dir = format!("{}/{}", state_dir, v);
+        find_roots_in(Path::new(&dir), store_dir, Path::new(&auto_dir), &mut roots)?;
+    }
+    Ok(roots)
+}
+
+fn find_roots_in(
+    path: &Path,
+    store_dir: &str,
+    auto_dir: &Path,
+    roots: &mut Roots,
+) -> std::io::Result<()> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if meta.is_dir() {
+        for v in std::fs::read_dir(path)? {
+            find_roots_in(&v?.path(), store_dir, auto_dir, roots)?;
+        }
+    } else if meta.file_type().is_symlink() {
+        let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+        let target = dir.join(std::fs::read_link(path)?);
+        if let Some(v) = to_store_path(store_dir, &target.to_string_lossy()) {
+            add_root(roots, v, path.display().to_string());
+            return Ok(());
+        }
+
+        // indirect root, e.g. `gcroots/auto/<hash>` pointing to the `result` link of nix-build
+        match std::fs::symlink_metadata(&target) {
+            Ok(v) if v.file_type().is_symlink() => {
+                let dir = target.parent().unwrap_or_else(|| Path::new("/"));
+                let store_path = dir.join(std::fs::read_link(&target)?);
+                if let Some(v) = to_store_path(store_dir, &store_path.to_string_lossy()) {
+                    add_root(roots, v, target.display().to_string());
+                }
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if path.starts_with(auto_dir) {
+                    debug!("removing stale link from '{}'", path.display());
+                    std::fs::remove_file(path)?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    } else if meta.is_file() {
+        // files named like a store path protect that path
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(v) = parse_base_name(&name) {
+            add_root(roots, v, path.display().to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Temporary roots of open connections. Files nobody holds a lock on are left behind by a
+/// crashed process and get removed.
+pub fn find_temp_roots(state_dir: &str, store_dir: &str, roots: &mut Roots) -> std::io::Result<()> {
+    let dir = format!("{}/temproots", state_dir.trim_end_matches('/'));
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file = match std::fs::File::open(entry.path()) {
+            Ok(v) => v,
+            // the connection closed in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if super::lock::lock_file(&file, super::lock::LockType::Write, false)? {
+            debug!("removing stale temporary roots file {}", name);
+            match std::fs::remove_file(entry.path()) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => continue,
+            }
+        }
+
+        let data = std::fs::read(entry.path())?;
+        // an entry without its terminator is still being written
+        let complete = data.iter().rposition(|v| *v == 0).map_or(0, |v| v + 1);
+        for v in data[..complete].split(|v| *v == 0) {
+            if let Some(v) = to_store_path(store_dir, &String::from_utf8_lossy(v)) {
+                add_root(roots, v, format!("{{temp:{}}}", name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Store paths used by running processes: their executables, working directories, open
+/// files, mapped libraries and environments. Processes whose `/proc` entries can not be read,
+/// like those of other users when not running as root, are skipped.
+pub fn find_runtime_roots(store_dir: &str, roots: &mut Roots) {
+    let mut found = |file: String, text: &str| {
+        for v in scan_store_paths(store_dir, text) {
+            add_root(roots, v, file.clone());
+        }
+    };
+
+    let procs = match std::fs::read_dir("/proc") {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("cannot find runtime roots: {}", e);
+            return;
+        }
+    };
+    for entry in procs.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.chars().all(|v| v.is_ascii_digit()) {
+            continue;
+        }
+        let dir = entry.path();
+
+        let mut links = vec![dir.join("exe"), dir.join("cwd")];
+        if let Ok(fds) = std::fs::read_dir(dir.join("fd")) {
+            links.extend(fds.flatten().map(|v| v.path()));
+        }
+        for v in links {
+            if let Ok(target) = std::fs::read_link(&v) {
+                found(v.display().to_string(), &target.to_string_lossy());
+            }
+        }
+
+        for v in &["maps", "environ"] {
+            let file = dir.join(v);
+            if let Ok(data) = std::fs::read(&file) {
+                found(file.display().to_string(), &String::from_utf8_lossy(&data));
+            }
+        }
+    }
+
+    for v in &[
+        "/proc/sys/kernel/modprobe",
+        "/proc/sys/kernel/fbsplash",
+        "/proc/sys/kernel/poweroff_cmd",
+    ] {
+        if let Ok(data) = std::fs::read_to_string(v) {
+            found(v.to_string(), &data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_roots, scan_store_paths, to_store_path, StorePath};
+
+    const FOO: &str = "ffffffffffffffffffffffffffffffff-foo";
+    const BAR: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bar";
+
+    #[test]
+    fn store_paths() {
+        let foo = StorePath::new(FOO).unwrap();
+        assert_eq!(
+            to_store_path("/nix/store", &format!("/nix/store/{}/bin/foo", FOO)),
+            Some(foo.clone())
+        );
+        assert_eq!(to_store_path("/nix/store", "/nix/store/.links"), None);
+        assert_eq!(to_store_path("/nix/store", "/nix/storefoo"), None);
+
+        let text = format!(
+            "PATH=/nix/store/{}/bin:/nix/store/{}\0HOME=/nix/store/eeee",
+            FOO, BAR
+        );
+        assert_eq!(
+            scan_store_paths("/nix/store", &text),
+            vec![foo, StorePath::new(BAR).unwrap()]
+        );
+    }
+
+    #[test]
+    fn links() {
+        let base = std::env::temp_dir().join(format!("nix-test-gc-roots-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let state_dir = base.join("var/nix");
+        let store_dir = base.join("store");
+        std::fs::create_dir_all(state_dir.join("gcroots/auto")).unwrap();
+        std::fs::create_dir_all(state_dir.join("profiles")).unwrap();
+        let store = store_dir.to_str().unwrap();
+
+        std::os::unix::fs::symlink(
+            store_dir.join(FOO).join("bin"),
+            state_dir.join("gcroots/foo"),
+        )
+        .unwrap();
+        // indirect root through a `result` link
+        std::os::unix::fs::symlink(store_dir.join(BAR), base.join("result")).unwrap();
+        std::os::unix::fs::symlink(base.join("result"), state_dir.join("gcroots/auto/1")).unwrap();
+        std::os::unix::fs::symlink(base.join("gone"), state_dir.join("gcroots/auto/2")).unwrap();
+        // profiles link to their generations relatively
+        std::os::unix::fs::symlink(
+            store_dir.join(BAR),
+            state_dir.join("profiles/system-1-link"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("system-1-link", state_dir.join("profiles/system")).unwrap();
+
+        let roots = find_roots(state_dir.to_str().unwrap(), store).unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[&StorePath::new(FOO).unwrap()].len(), 1);
+        assert_eq!(roots[&StorePath::new(BAR).unwrap()].len(), 3);
+        // stale links in `auto` are removed
+        assert!(std::fs::symlink_metadata(state_dir.join("gcroots/auto/2")).is_err());
+        assert!(std::fs::symlink_metadata(state_dir.join("gcroots/auto/1")).is_ok());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -40,6 +40,17 @@ pub trait Logger: AsyncWrite {
         }))
     }
 
+    /// Show `msg` to the user, only possible between `start_work` and `stop_work`
+    fn log<'a>(&'a self, msg: &'a str) -> LocalFutureObj<'a, EmptyResult> {
+        LocalFutureObj::new(Box::new(async move {
+            if self.can_send() {
+                self.write_u64(STDERR::NEXT as u64).await?;
+                self.write_string(&format!("{}\n", msg)).await?;
+            }
+            Ok(())
+        }))
+    }
+
     fn stop_work<'a>(&'a self, state: WorkFinish) -> LocalFutureObj<'a, EmptyResult> {
         LocalFutureObj::new(Box::new(async move {
             self.set_can_send(false);
@@ -48,9 +59,7 @@ pub trait Logger: AsyncWrite {
                 WorkFinish::Error(msg, s) => {
                     self.write_u64(STDERR::ERROR as u64).await?;
                     self.write_string(&msg).await?;
-                    if s != 0 {
-                        self.write_u64(s as u64).await?;
-                    }
+                    self.write_u64(s as u64).await?;
                 }
                 WorkFinish::Done => {
                     self.write_u64(STDERR::LAST as u64).await?;
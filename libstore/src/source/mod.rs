@@ -13,6 +13,9 @@ use std::boxed::Box;
 mod logger;
 pub use logger::{Logger, WorkFinish, STDERR};
 
+mod stdio;
+pub use stdio::Stdio;
+
 /// Shortcut for `WorkFinish::Done`
 pub const WORKDONE: WorkFinish = WorkFinish::Done;
 
@@ -111,6 +114,21 @@ pub trait AsyncWrite {
         }))
     }
 
+    /// Write all of `buf`, `write` may accept only a part of it
+    fn write_all<'a>(&'a self, buf: &'a [u8]) -> LocalFutureObj<'a, EmptyResult> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut written = 0;
+            while written < buf.len() {
+                let v = self.write(&buf[written..]).await?;
+                if v == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::WriteZero));
+                }
+                written += v;
+            }
+            Ok(())
+        }))
+    }
+
     fn write_bool<'a>(&'a self, v: bool) -> LocalFutureObj<'a, EmptyResult> {
         self.write_u64(v as u64)
     }
@@ -128,6 +146,17 @@ pub trait AsyncWrite {
         }))
     }
 
+    /// Like `write_string`, for data which is not valid UTF-8
+    fn write_os_string<'a>(&'a self, data: &'a [u8]) -> LocalFutureObj<'a, EmptyResult> {
+        LocalFutureObj::new(Box::new(async move {
+            self.write_u64(data.len() as u64).await?;
+            self.write_all(data).await?;
+            self.write_padding(data.len()).await?;
+
+            Ok(())
+        }))
+    }
+
     fn write_padding<'a>(&'a self, len: usize) -> LocalFutureObj<'a, EmptyResult> {
         LocalFutureObj::new(Box::new(async move {
             if len % 8 != 0 {
@@ -182,6 +211,9 @@ pub struct Connection {
     // logger types
     pub can_send: Arc<std::sync::atomic::AtomicBool>,
     pub pending_msgs: Arc<Mutex<Vec<String>>>,
+
+    /// Maximum and number of bytes read since the limit was set
+    pub read_limit: Arc<Mutex<Option<(u64, u64)>>>,
 }
 
 impl Connection {
@@ -194,6 +226,8 @@ impl Connection {
 
             can_send: Arc::new(std::sync::atomic::AtomicBool::new(false)),
             pending_msgs: Arc::new(Mutex::new(Vec::new())),
+
+            read_limit: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -206,6 +240,8 @@ impl Connection {
 
             can_send: Arc::new(std::sync::atomic::AtomicBool::new(false)),
             pending_msgs: Arc::new(Mutex::new(Vec::new())),
+
+            read_limit: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -248,6 +284,31 @@ impl Connection {
         }
     }
 
+    /// Fail reads once more than `max` bytes were read, `None` removes the limit
+    pub fn set_read_limit(&self, max: Option<u64>) {
+        *self.read_limit.lock().unwrap() = max.map(|v| (v, 0));
+    }
+
+    fn check_read_limit(&self, len: usize) -> Result<(), std::io::Error> {
+        let mut limit = self.read_limit.lock().unwrap();
+        if let Some((max, read)) = &mut *limit {
+            *read += len as u64;
+            if *read > *max {
+                // the rest of the upload is never read, the stream is unusable now
+                self.set_can_send(false);
+                let e = crate::StoreError::UploadTooLarge {
+                    size: *read,
+                    max: *max,
+                };
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    e.to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_tunnel(&self, tunnel: bool) -> bool {
         self.tunnelsource.swap(tunnel, Ordering::Relaxed)
     }
@@ -267,6 +328,7 @@ impl AsyncRead for Connection {
             if buf.len() < len {
                 return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
             }
+            self.check_read_limit(len)?;
 
             // detect if we are a tunnelsource
             let size = if self.get_tunnel() {
@@ -347,12 +409,14 @@ impl Logger for Connection {
 
 #[cfg(test)]
 pub(crate) mod test {
-    use super::{Arc, AsyncRead, AsyncReadExt, AsyncWrite, Box, LocalFutureObj, Mutex};
+    use super::{Arc, AsyncRead, AsyncReadExt, AsyncWrite, Box, LocalFutureObj, Logger, Mutex};
     use std::io::Cursor;
+    use std::sync::atomic::{AtomicBool, Ordering};
     pub struct Connection {
         pub reader: Arc<Mutex<Cursor<Vec<u8>>>>,
         pub writer: Arc<Mutex<Cursor<Vec<u8>>>>,
         pub tunnel: bool,
+        pub can_send: AtomicBool,
     }
 
     impl Connection {
@@ -361,6 +425,7 @@ pub(crate) mod test {
                 reader: Arc::new(Mutex::new(Cursor::new(vec))),
                 writer: Arc::new(Mutex::new(Cursor::new(Vec::new()))),
                 tunnel,
+                can_send: AtomicBool::new(false),
             }
         }
 
@@ -400,6 +465,22 @@ pub(crate) mod test {
         }
     }
 
+    impl Logger for Connection {
+        fn can_send(&self) -> bool {
+            self.can_send.load(Ordering::Relaxed)
+        }
+
+        fn set_can_send(&self, can: bool) {
+            self.can_send.store(can, Ordering::Relaxed)
+        }
+
+        fn enqueu(&self, _msg: String) {}
+
+        fn dequeu(&self) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
     #[tokio::test]
     async fn read_u64() {
         let con = Connection::new(vec![2, 0, 0, 0, 0, 0, 0, 0], false);
@@ -476,5 +557,45 @@ pub(crate) mod test {
         assert_eq!(writer.get_ref(), &vec);
     }
 
+    #[tokio::test]
+    async fn stop_work_error() {
+        let con = Connection::new_empty(false);
+
+        con.start_work().await.unwrap();
+        assert!(con.can_send());
+        con.stop_work(super::WorkFinish::Error("no".to_string(), 1))
+            .await
+            .unwrap();
+        assert!(!con.can_send());
+
+        let writer = con.writer.lock().unwrap();
+        #[rustfmt::skip]
+        let vec: Vec<u8> = vec![
+            0x70, 0x74, 0x78, 0x63, 0, 0, 0, 0, // STDERR_ERROR
+            2, 0, 0, 0, 0, 0, 0, 0,
+            110, 111, 0, 0, 0, 0, 0, 0,
+            1, 0, 0, 0, 0, 0, 0, 0, // status
+        ];
+        assert_eq!(writer.get_ref(), &vec);
+    }
+
+    #[tokio::test]
+    async fn read_limit() {
+        use tokio::io::AsyncWriteExt;
+
+        let (server, mut client) = tokio::net::UnixStream::pair().unwrap();
+        client.write_all(&[0; 24]).await.unwrap();
+
+        let con = super::Connection::new(server);
+        con.set_read_limit(Some(16));
+        con.read_u64().await.unwrap();
+        con.read_u64().await.unwrap();
+        let e = con.read_u64().await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        con.set_read_limit(None);
+        con.read_u64().await.unwrap();
+    }
+
     // TODO: logger tests
 }
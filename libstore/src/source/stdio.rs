@@ -0,0 +1,55 @@
+use tokio::sync::Mutex;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{AsyncRead, AsyncWrite, Box, LocalFutureObj};
+
+/// Wire protocol on stdin and stdout, e.g. for `nix-store --serve` behind ssh
+pub struct Stdio {
+    reader: Mutex<tokio::io::Stdin>,
+    writer: Mutex<tokio::io::Stdout>,
+}
+
+impl Stdio {
+    pub fn new() -> Self {
+        Self {
+            reader: Mutex::new(tokio::io::stdin()),
+            writer: Mutex::new(tokio::io::stdout()),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncRead for Stdio {
+    fn read_exact<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+        len: usize,
+    ) -> LocalFutureObj<'a, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move {
+            if buf.len() < len {
+                return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            let mut reader = self.reader.lock().await;
+            reader.read_exact(&mut buf[0..len]).await
+        }))
+    }
+}
+
+impl AsyncWrite for Stdio {
+    fn write<'a>(&'a self, buf: &'a [u8]) -> LocalFutureObj<'a, Result<usize, std::io::Error>> {
+        LocalFutureObj::new(Box::new(async move {
+            // the other side waits for our answer, so nothing may stay buffered
+            let mut writer = self.writer.lock().await;
+            writer.write_all(buf).await?;
+            writer.flush().await?;
+            Ok(buf.len())
+        }))
+    }
+}
@@ -81,7 +81,6 @@ impl Hash {
         match *v.get(0).unwrap_or(&"") {
             "sha256" => {
                 trace!("decoding sha hash: {}", v.get(1).unwrap());
-                println!("decoding sha hash: {}", v.get(1).unwrap());
                 let data =
                     data_encoding::HEXLOWER_PERMISSIVE.decode(v.get(1).unwrap().as_bytes())?;
                 //BASE32.decode(v.get(1).unwrap().as_bytes())?;
@@ -134,7 +133,6 @@ impl std::convert::TryFrom<&str> for Hash {
         match *v.get(0).unwrap_or(&"") {
             "sha256" => {
                 trace!("decoding sha hash: {}", v.get(1).unwrap());
-                println!("decoding sha hash: {}", v.get(1).unwrap());
                 let data = base32::decode(v.get(1).unwrap())?;
                 //BASE32.decode(v.get(1).unwrap().as_bytes())?;
                 /*let mut buf: [u8; 32] = [0; 32];
@@ -11,7 +11,10 @@ use std::convert::TryFrom;
 use super::path::StorePathWithOutputs;
 use super::{BuildStore, ReadStore, Store, StorePath, WriteStore};
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Number of paths checked with one query by `query_valid_paths`
+const VALID_PATHS_BATCH: usize = 500;
 
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
@@ -20,8 +23,18 @@ pub struct LocalStore {
     params: std::collections::HashMap<String, super::Param>,
 
     sqlite: Arc<RwLock<rusqlite::Connection>>,
+    /// Created with the first temp root, the daemon opens one store per connection
+    temp_roots: Arc<Mutex<Option<crate::gc::TempRoots>>>,
+    /// `auto-optimise-store`, unless the `auto-optimise-store` param overrides it
+    auto_optimise: bool,
 }
 
+/// Paths invalidated in one transaction by the garbage collector
+const GC_BATCH_SIZE: usize = 100;
+
+/// Valid paths and the paths they keep alive
+type References = std::collections::HashMap<StorePath, Vec<StorePath>>;
+
 impl LocalStore {
     pub async fn open_store(
         path: &str,
@@ -37,10 +50,16 @@ impl LocalStore {
             path
         ))?));
 
+        let auto_optimise = match params.get("auto-optimise-store") {
+            Some(super::Param::Bool(v)) => *v,
+            _ => crate::CONFIG.read().unwrap().auto_optimise_store,
+        };
         let store = Self {
             base_dir: path.to_string(),
             params,
             sqlite,
+            temp_roots: Arc::new(Mutex::new(None)),
+            auto_optimise,
         };
 
         store.make_store_writable().await?;
@@ -97,6 +116,133 @@ impl LocalStore {
         Ok(())
     }
 
+    /// Hard-link the files of a newly registered path into `.links`, for `auto-optimise-store`
+    async fn optimise_new_path(&self, path: &str) -> Result<(), StoreError> {
+        let mut optimiser = super::Optimiser::new(&self.get_store_dir())?;
+        optimiser.optimise_path(std::path::Path::new(path)).await?;
+        trace!("optimised {}: {:?}", path, optimiser.stats);
+        Ok(())
+    }
+
+    /// Remove `paths` from the database in one transaction, their contents are left on disk
+    fn invalidate_paths(&self, paths: &[StorePath]) -> Result<(), StoreError> {
+        let sqlite = self.sqlite.write().unwrap();
+        let transaction = rusqlite::Transaction::new_unchecked(
+            &sqlite,
+            rusqlite::TransactionBehavior::Immediate,
+        )?;
+        for path in paths {
+            let path = format!("{}/{}", self.get_store_dir(), path);
+            debug!("invalidating path '{}'", path);
+            // foreign keys are not enabled, so nothing cascades
+            transaction.execute(
+                "DELETE FROM Refs WHERE referrer = (SELECT id FROM ValidPaths WHERE path = (?));",
+                &[&path],
+            )?;
+            transaction.execute(
+                "DELETE FROM DerivationOutputs WHERE drv = (SELECT id FROM ValidPaths WHERE path = (?));",
+                &[&path],
+            )?;
+            transaction.execute("DELETE FROM ValidPaths WHERE path = (?);", &[&path])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// The references of every valid path, and the edges of the garbage collector: the
+    /// references plus derivers and derivation outputs, following `keep-derivations` and `keep-outputs`
+    fn query_gc_graph(&self) -> Result<(References, References), StoreError> {
+        let conf = crate::CONFIG.read().unwrap();
+        let (keep_outputs, keep_derivations) = (conf.gc_keep_outputs, conf.gc_keep_derivations);
+        drop(conf);
+
+        let parse = |v: String| StorePath::new(v.rsplit('/').next().unwrap_or_default());
+        let sqlite = self.sqlite.read().unwrap();
+        let mut references = References::new();
+        let mut derivers = Vec::new();
+        let mut stm = sqlite.prepare("SELECT path, deriver FROM ValidPaths;")?;
+        let rows = stm.query_map(rusqlite::NO_PARAMS, |row| {
+            Ok((
+                row.get::<usize, String>(0)?,
+                row.get::<usize, Option<String>>(1)?,
+            ))
+        })?;
+        for v in rows {
+            let (path, deriver) = v?;
+            let path = parse(path)?;
+            if let Some(deriver) = deriver.filter(|v| !v.is_empty()) {
+                derivers.push((path.clone(), parse(deriver)?));
+            }
+            references.insert(path, Vec::new());
+        }
+        drop(stm);
+
+        let mut stm = sqlite.prepare(
+            "SELECT referrer.path, reference.path FROM Refs \
+             JOIN ValidPaths referrer ON referrer.id = Refs.referrer \
+             JOIN ValidPaths reference ON reference.id = Refs.reference;",
+        )?;
+        let rows = stm.query_map(rusqlite::NO_PARAMS, |row| {
+            Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+        })?;
+        for v in rows {
+            let (referrer, reference) = v?;
+            references
+                .entry(parse(referrer)?)
+                .or_default()
+                .push(parse(reference)?);
+        }
+        drop(stm);
+
+        let mut edges = references.clone();
+        if keep_derivations {
+            for (path, deriver) in derivers {
+                if references.contains_key(&deriver) {
+                    edges.entry(path).or_default().push(deriver);
+                }
+            }
+        }
+        if keep_outputs {
+            let mut stm = sqlite.prepare(
+                "SELECT ValidPaths.path, DerivationOutputs.path FROM DerivationOutputs \
+                 JOIN ValidPaths ON ValidPaths.id = DerivationOutputs.drv;",
+            )?;
+            let rows = stm.query_map(rusqlite::NO_PARAMS, |row| {
+                Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+            })?;
+            for v in rows {
+                let (drv, output) = v?;
+                edges.entry(parse(drv)?).or_default().push(parse(output)?);
+            }
+        }
+
+        Ok((references, edges))
+    }
+
+    /// Invalidate `paths` in one transaction, then delete them from disk
+    fn delete_garbage(
+        &self,
+        paths: &[(StorePath, u64)],
+        references: &References,
+        results: &mut crate::gc::GCResults,
+    ) -> Result<(), StoreError> {
+        let valid: Vec<StorePath> = paths
+            .iter()
+            .map(|(v, _)| v.clone())
+            .filter(|v| references.contains_key(v))
+            .collect();
+        self.invalidate_paths(&valid)?;
+
+        for (path, size) in paths {
+            let path_str = format!("{}/{}", self.get_store_dir(), path);
+            debug!("deleting '{}'", path_str);
+            crate::gc::delete_tree(std::path::Path::new(&path_str))?;
+            results.paths.push(path.clone());
+            results.bytes_freed += size;
+        }
+        Ok(())
+    }
+
     pub fn get_state_dir(&self) -> String {
         format!("{}var/nix/", self.base_dir)
     }
@@ -132,110 +278,206 @@ impl BuildStore for Arc<LocalStore> {
         LocalFutureObj::new(Box::new(async move {
             info!("quering info about missing paths");
 
-            use std::sync::{Arc, Mutex};
+            use std::sync::Mutex;
             let state = Arc::new(Mutex::new(super::MissingInfo::new()));
+            let substitute = crate::CONFIG.read().unwrap().substitute;
 
-            /*let do_path = |path: &'a StorePathWithOutputs,
-                           state: Arc<Mutex<super::MissingInfo>>,
-                           store: Arc<LocalStore>,
-                           substitute: bool| async move {
-                let mut state_l = state.lock().unwrap();
-                if state_l.done.contains(&path.path.name()) {
-                    return Ok(());
-                }
-                state_l.done.push(path.path.name());
-                drop(state_l);
-
-                println!("working on {}", path.path.name());
+            let work: Vec<_> = paths
+                .iter()
+                .map(|v| do_path(v.clone(), state.clone(), self.clone(), substitute))
+                .collect();
+            let ret: Result<Vec<()>, StoreError> =
+                futures::future::join_all(work).await.into_iter().collect();
+            ret?;
 
-                if path.path.is_derivation() {
-                    if !store.is_valid_path(&path.path).await? {
-                        //insert into unknown
-                        // TODO: we could try to substitute the drv
-                        let mut state_l = state.lock().unwrap();
-                        state_l.unknown.push(path.path.clone());
-                        return Ok(());
-                    }
-                    let drv = crate::build::derivation::Derivation::from_path(&path.path).await?;
-                    let drv =
-                        crate::build::derivation::ParsedDerivation::new(path.path.clone(), drv)?;
-
-                    // TODO:
-                    /*
-                    PathSet invalid;
-                    for (auto & j : drv->outputs)
-                        if (wantOutput(j.first, path.outputs)
-                            && !isValidPath(j.second.path))
-                            invalid.insert(printStorePath(j.second.path));
-                    if (invalid.empty()) return;*/
-                    let mut invalid = crate::store::path::StorePaths::new();
-                    for (name, out) in &drv.derivation.outputs {
-                        if path.outputs.contains(name) && !store.is_valid_path(&out.path).await? {
-                            trace!("adding {} to doto list", self.print_store_path(&out.path));
-                            invalid.push(out.path.clone());
-                        }
-                    }
-                    if invalid.is_empty() {
-                        return Ok(());
-                    }
+            let mut state: super::MissingInfo =
+                Arc::try_unwrap(state).unwrap().into_inner().unwrap();
+            state.will_build.sort();
+            state.will_substitute.sort();
+            state.unknown.sort();
+            Ok(state)
+        }))
+    }
 
-                    if substitute && drv.substitutes_allowed() {
-                        for v in invalid {
-                            debug!("check for subst: {}", v);
-                            //do_path(v, state.clone(), store.clone(), substitute).await?;
-                            unimplemented!("qeue path");
-                            // https://source.kloenk.de/github.com/NixOS/nix@9223603908abaa62711296aa224e1bc3d7fb0a91/-/blob/src/libstore/misc.cc?utm_source=share#L151
-                        }
-                    } else {
-                        unimplemented!("no substitute enabled");
-                    }
-                } else {
-                    warn!("non derivation path: {}", self.print_store_path(&path.path));
-                    /*
-                    if (isValidPath(path.path)) return;
-
-                    SubstitutablePathInfos infos;
-                    querySubstitutablePathInfos({path.path}, infos);
-
-                    if (infos.empty()) {
-                        auto state(state_.lock());
-                        state->unknown.insert(path.path);
-                        return;
-                    }
+    /// The builder runs on the host without a sandbox, as a build user if `build-users-group`
+    /// is set. Only `BuildMode::Normal` is supported, the other modes fail with `MiscFailure`.
+    fn build_derivation<'a>(
+        &'a self,
+        drv_path: &'a StorePath,
+        drv: &'a crate::build::derivation::Derivation,
+        mode: super::BuildMode,
+        options: &'a super::BuildOptions,
+    ) -> LocalFutureObj<'a, Result<super::BuildResult, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            use super::{BuildMode, BuildResult, BuildStatus};
+            use crate::build::local;
+            info!("building derivation {}", drv_path);
+            let start_time = chrono::Utc::now().timestamp();
+            let finish = |status, msg: &str| {
+                let mut result = BuildResult::new(status, msg);
+                result.start_time = start_time;
+                result.stop_time = chrono::Utc::now().timestamp();
+                result
+            };
+            let drv_name = self.print_store_path(drv_path);
+
+            // unlike build_paths the inputs are not built, the client has to send them first
+            let mut missing = Vec::new();
+            for v in &drv.input_srcs {
+                if !self.is_valid_path(v).await? {
+                    missing.push(self.print_store_path(v));
+                }
+            }
+            if !missing.is_empty() {
+                return Ok(finish(
+                    BuildStatus::DependencyFailed,
+                    &format!(
+                        "some dependencies of '{}' are missing: {}",
+                        drv_name,
+                        missing.join(", ")
+                    ),
+                ));
+            }
 
-                    auto info = infos.find(path.path);
-                    assert(info != infos.end());
+            if mode != BuildMode::Normal {
+                return Ok(finish(
+                    BuildStatus::MiscFailure,
+                    &format!(
+                        "building '{}' in {:?} mode is not supported yet",
+                        drv_name, mode
+                    ),
+                ));
+            }
 
-                    {
-                        auto state(state_.lock());
-                        state->willSubstitute.insert(path.path);
-                        state->downloadSize += info->second.downloadSize;
-                        state->narSize += info->second.narSize;
-                    }
+            // a concurrent build of the same outputs would delete them
+            let mut locks = Vec::new();
+            for v in drv.outputs.values() {
+                let lock = format!("{}.lock", self.print_store_path(&v.path));
+                let file = std::fs::File::create(&lock)?;
+                if !crate::gc::lock::lock_file(&file, crate::gc::lock::LockType::Write, false)? {
+                    return Ok(finish(
+                        BuildStatus::TransientFailure,
+                        &format!("'{}' is being built by another process", drv_name),
+                    ));
+                }
+                locks.push((lock, file));
+            }
 
-                    for (auto & ref : info->second.references)
-                        pool.enqueue(std::bind(doPath, StorePathWithOutputs { ref }));
-                        */
+            let mut valid = 0;
+            for v in drv.outputs.values() {
+                if self.is_valid_path(&v.path).await? {
+                    valid += 1;
                 }
+            }
+            let config = crate::CONFIG.read().unwrap().clone();
+            let refused = if valid == drv.outputs.len() {
+                Some((BuildStatus::AlreadyValid, String::new()))
+            } else if valid != 0 {
+                Some((
+                    BuildStatus::MiscFailure,
+                    format!(
+                        "some outputs of '{}' are valid, building only the others is not supported yet",
+                        drv_name
+                    ),
+                ))
+            } else if drv.is_builtin() {
+                Some((
+                    BuildStatus::MiscFailure,
+                    format!(
+                        "the builder '{}' of '{}' is not supported yet",
+                        drv.builder, drv_name
+                    ),
+                ))
+            } else if drv.platform != config.system {
+                Some((
+                    BuildStatus::MiscFailure,
+                    format!(
+                        "a '{}' is required to build '{}', but I am a '{}'",
+                        drv.platform, drv_name, config.system
+                    ),
+                ))
+            } else if config.sandbox == "true" && !config.sandbox_fallback {
+                Some((
+                    BuildStatus::MiscFailure,
+                    format!(
+                        "sandboxed builds are not supported yet, '{}' needs 'sandbox-fallback'",
+                        drv_name
+                    ),
+                ))
+            } else {
+                None
+            };
+            if let Some((status, msg)) = refused {
+                remove_locks(locks);
+                return Ok(finish(status, &msg));
+            }
+            if config.sandbox == "true" {
+                warn!("building '{}' without a sandbox", drv_name);
+            }
 
-                /*return Err(StoreError::Unimplemented {
-                    msg: "unimplemented".to_string(),
-                });*/
-                Ok(())
-            };*/
+            for v in drv.outputs.values() {
+                self.add_temp_root(&v.path).await?;
+                // left over from an interrupted build
+                crate::gc::delete_tree(std::path::Path::new(&self.print_store_path(&v.path)))?;
+            }
 
-            let mut work = Vec::new();
-            for v in paths {
-                work.push(do_path(v.clone(), state.clone(), self.clone(), true));
-                // TODO: substitute from settings
+            let user = if !config.build_users_group.is_empty() && unsafe { libc::getuid() } == 0 {
+                Some(crate::build::user::UserLock::find_free_user()?)
+            } else {
+                None
+            };
+            let name = drv_path.name();
+            let build_dir = local::create_build_dir(name.trim_end_matches(".drv"))?;
+            if let Some(user) = &user {
+                nix::unistd::chown(
+                    &build_dir,
+                    Some(nix::unistd::Uid::from_raw(user.get_uid())),
+                    Some(nix::unistd::Gid::from_raw(user.get_gid())),
+                )
+                .map_err(|e| StoreError::SysError {
+                    msg: format!("changing the owner of '{}': {}", build_dir.display(), e),
+                })?;
             }
 
-            let ret: Result<Vec<()>, StoreError> =
-                futures::future::join_all(work).await.into_iter().collect();
-            ret?;
+            let store_dir = self.get_store_dir()?;
+            let exit = local::run(&name, drv, &store_dir, &build_dir, user.as_ref(), options).await;
+            // kills whatever the builder left running
+            drop(user);
+            let result = match exit {
+                Ok(local::Exit::Success) => register_outputs(self, drv_path, drv)
+                    .await
+                    .map(|v| v.map(|v| (BuildStatus::OutputRejected, v))),
+                Ok(local::Exit::Failed(v)) => Ok(Some((
+                    BuildStatus::PermanentFailure,
+                    format!("builder for '{}' failed with {}", drv_name, v),
+                ))),
+                Ok(local::Exit::TimedOut(v)) => Ok(Some((
+                    BuildStatus::TimedOut,
+                    format!("building '{}' {}", drv_name, v),
+                ))),
+                Err(e) => Err(e),
+            };
+
+            let failed = !matches!(result, Ok(None));
+            if failed {
+                for v in drv.outputs.values() {
+                    if !self.is_valid_path(&v.path).await? {
+                        let path = self.print_store_path(&v.path);
+                        crate::gc::delete_tree(std::path::Path::new(&path))?;
+                    }
+                }
+            }
+            if failed && config.keep_failed {
+                info!("keeping build directory '{}'", build_dir.display());
+            } else if let Err(e) = crate::gc::delete_tree(&build_dir) {
+                warn!("could not remove '{}': {}", build_dir.display(), e);
+            }
+            remove_locks(locks);
 
-            let state: super::MissingInfo = Arc::try_unwrap(state).unwrap().into_inner().unwrap();
-            Ok(state)
+            Ok(match result? {
+                Some((status, msg)) => finish(status, &msg),
+                None => finish(BuildStatus::Built, ""),
+            })
         }))
     }
 
@@ -367,15 +609,13 @@ impl WriteStore for Arc<LocalStore> {
 
                 let out = self.print_store_path(&path.path);
 
-                con.set_tunnel(true);
                 con.set_hasher()?;
                 // TODO: HashModuloSink
                 let parser = crate::archive::NarParser::new(&out, con, self.box_clone_write());
-                parser.parse().await.unwrap(); // TODO: parse error
-
-                con.set_tunnel(false);
-
+                let parsed = parser.parse().await;
+                // the hasher is part of the connection, never leave it set for the next op
                 let hasher = con.pop_hasher()?;
+                parsed?;
                 if hasher.hash != path.nar_hash
                 /*|| hasher.size != path.nar_size*/
                 {
@@ -445,81 +685,204 @@ impl WriteStore for Arc<LocalStore> {
                 });
             }*/ // TODO: should fail while parsing to an StorePath
 
-            let sqlite = self.sqlite.write().unwrap();
-            //let mut stm = sqlite.prepare("INSERT INTO ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, sigs, ca) values (?, ?, ?, ?, ?, ?, ?, ?);")?; // TODO: prepare those in a state object or so
-
-            //let data = stm.execute(&path.display().to_string(), &info.nar_hash.to_sql_string(), &info.registration_time.timestamp().to_string(), &deriver, &nar_size, ]);
-            /*let data = stm.execute(
-                rusqlite::params![
-                    path.display().to_string(),
-                    info.nar_hash.to_sql_string(),
-                    info.registration_time.timestamp(),
-                    deriver,
-                    nar_size,
-                    info.ultimate,
-                    "", // TODO: sigs
-                    "", // TODO: CA
-                ]
-            )?;*/
-
-            //sqlite.execute("INSERT INTO ValidPaths (path, hash, registrationTime, registrationTime) values (?path", )
-            /*let mut params = rusqlite::named_params! {
-                ":path": path.display().to_string(),
-                ":hash": info.nar_hash.to_sql_string(),
-                ":registrationTime": info.registration_time.timestamp().to_string(),
-
-            };*/
+            // parse before taking the lock, the outputs are cached in DerivationOutputs
+            let drv = if info.path.is_derivation() {
+                let text = tokio::fs::read_to_string(self.print_store_path(&info.path)).await?;
+                match crate::build::derivation::Derivation::from_str(&text, self) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        // the path is valid anyway, only its outputs are not cached
+                        warn!("not caching the outputs of {}: {}", info.path, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
             let path_str = self.print_store_path(&info.path);
-            let hash = info.nar_hash.to_sql_string();
-            let reg_time = info.registration_time.timestamp();
-            let mut deriver = String::new();
-            let mut nar_size = 0;
-            let mut vec: Vec<(&str, &dyn rusqlite::ToSql)> = vec![
-                (":path", &path_str),
-                (":hash", &hash),
-                (":registrationTime", &reg_time),
-            ];
-            if let Some(v) = &info.deriver {
-                deriver = self.print_store_path(v);
-                vec.push((":deriver", &deriver));
-            }
-            if let Some(nar) = info.nar_size {
-                nar_size = nar as i64; //  u64 is not supported
-                vec.push((":narSize", &nar_size));
-            }
-            if info.ultimate {
-                vec.push((":ultimate", &1));
-            }
-            // TODO: sigs, ca
-            let data = sqlite.execute_named("INSERT INTO ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, sigs, ca) values (:path, :hash, :registrationTime, :deriver, :narSize, :ultimate, :sigs, :ca);", &vec)?; // TODO: prepare those in a state object or so
-
-            trace!("data: {:?}", data);
-
-            let info = sqlite.query_row(
-                "SELECT id FROM ValidPaths WHERE path = (?)",
-                &[&path_str],
-                move |row| {
-                    let mut info = info.clone();
-                    let id = row.get::<usize, isize>(0)?;
-                    info.id = id as u64;
-                    Ok(info)
-                },
-            )?;
-            // TODO: references
-            if info.references.len() != 0 {
-                unimplemented!()
+            let info = {
+                let sqlite = self.sqlite.write().unwrap();
+                // the path and its references become valid together
+                let transaction = rusqlite::Transaction::new_unchecked(
+                    &sqlite,
+                    rusqlite::TransactionBehavior::Immediate,
+                )?;
+                //let mut stm = sqlite.prepare("INSERT INTO ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, sigs, ca) values (?, ?, ?, ?, ?, ?, ?, ?);")?; // TODO: prepare those in a state object or so
+
+                //let data = stm.execute(&path.display().to_string(), &info.nar_hash.to_sql_string(), &info.registration_time.timestamp().to_string(), &deriver, &nar_size, ]);
+                /*let data = stm.execute(
+                    rusqlite::params![
+                        path.display().to_string(),
+                        info.nar_hash.to_sql_string(),
+                        info.registration_time.timestamp(),
+                        deriver,
+                        nar_size,
+                        info.ultimate,
+                        "", // TODO: sigs
+                        "", // TODO: CA
+                    ]
+                )?;*/
+
+                //sqlite.execute("INSERT INTO ValidPaths (path, hash, registrationTime, registrationTime) values (?path", )
+                /*let mut params = rusqlite::named_params! {
+                    ":path": path.display().to_string(),
+                    ":hash": info.nar_hash.to_sql_string(),
+                    ":registrationTime": info.registration_time.timestamp().to_string(),
+
+                };*/
+                let hash = info.nar_hash.to_sql_string();
+                let reg_time = info.registration_time.timestamp();
+                let mut deriver = String::new();
+                let mut nar_size = 0;
+                let mut vec: Vec<(&str, &dyn rusqlite::ToSql)> = vec![
+                    (":path", &path_str),
+                    (":hash", &hash),
+                    (":registrationTime", &reg_time),
+                ];
+                if let Some(v) = &info.deriver {
+                    deriver = self.print_store_path(v);
+                    vec.push((":deriver", &deriver));
+                }
+                if let Some(nar) = info.nar_size {
+                    nar_size = nar as i64; //  u64 is not supported
+                    vec.push((":narSize", &nar_size));
+                }
+                if info.ultimate {
+                    vec.push((":ultimate", &1));
+                }
+                let sigs = info.sigs.join(" ");
+                vec.push((":sigs", &sigs));
+                if let Some(v) = &info.ca {
+                    vec.push((":ca", v));
+                }
+                let data = transaction.execute_named("INSERT INTO ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, sigs, ca) values (:path, :hash, :registrationTime, :deriver, :narSize, :ultimate, :sigs, :ca);", &vec)?; // TODO: prepare those in a state object or so
+
+                trace!("data: {:?}", data);
+
+                let info = transaction.query_row(
+                    "SELECT id FROM ValidPaths WHERE path = (?)",
+                    &[&path_str],
+                    move |row| {
+                        let mut info = info.clone();
+                        let id = row.get::<usize, isize>(0)?;
+                        info.id = id as u64;
+                        Ok(info)
+                    },
+                )?;
+                if let Some(drv) = drv {
+                    let mut stm = transaction.prepare_cached(
+                        "INSERT OR REPLACE INTO DerivationOutputs (drv, id, path) VALUES (?, ?, ?);",
+                    )?;
+                    for (name, output) in &drv.outputs {
+                        stm.execute(rusqlite::params![
+                            info.id as i64,
+                            name,
+                            self.print_store_path(&output.path)
+                        ])?;
+                    }
+                }
+
+                if !info.references.is_empty() {
+                    let mut stm = transaction.prepare_cached(
+                        "INSERT OR IGNORE INTO Refs (referrer, reference) VALUES (?, ?);",
+                    )?;
+                    for reference in &info.references {
+                        // a path may refer to itself, it was inserted above
+                        let reference = self.print_store_path(reference);
+                        let id: i64 = match transaction.query_row(
+                            "SELECT id FROM ValidPaths WHERE path = (?);",
+                            &[&reference],
+                            |row| row.get(0),
+                        ) {
+                            Ok(v) => v,
+                            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                                return Err(StoreError::InvalidPath { path: reference })
+                            }
+                            Err(e) => return Err(e.into()),
+                        };
+                        stm.execute(rusqlite::params![info.id as i64, id])?;
+                    }
+                }
+                transaction.commit()?;
+                info
+            };
+
+            if self.auto_optimise {
+                // the path is valid already, failing to deduplicate it only costs disk space
+                if let Err(e) = self.optimise_new_path(&path_str).await {
+                    warn!("could not optimise {}: {}", path_str, e);
+                }
             }
 
             Ok(info)
         }))
     }
 
+    fn add_signatures<'a>(
+        &'a self,
+        path: &'a StorePath,
+        sigs: &'a [String],
+    ) -> LocalFutureObj<'a, Result<(), StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let path = self.print_store_path(path);
+            let sqlite = self.sqlite.write().unwrap();
+            // other processes may add signatures to the same path
+            let transaction = rusqlite::Transaction::new_unchecked(
+                &sqlite,
+                rusqlite::TransactionBehavior::Immediate,
+            )?;
+
+            let current: Option<String> = match transaction.query_row(
+                "SELECT sigs FROM ValidPaths WHERE path = (?);",
+                &[&path],
+                |row| row.get(0),
+            ) {
+                Ok(v) => v,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    return Err(StoreError::InvalidPath { path })
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut merged: Vec<String> = Vec::new();
+            let current = current.unwrap_or_default();
+            for v in current
+                .split_whitespace()
+                .chain(sigs.iter().map(|v| v.as_str()))
+            {
+                if !v.is_empty() && !merged.iter().any(|m| m == v) {
+                    merged.push(v.to_string());
+                }
+            }
+
+            transaction.execute(
+                "UPDATE ValidPaths SET sigs = (?) WHERE path = (?);",
+                &[&merged.join(" "), &path],
+            )?;
+            transaction.commit()?;
+
+            Ok(())
+        }))
+    }
+
     fn add_temp_root<'a>(
         &'a self,
         path: &'a StorePath,
     ) -> LocalFutureObj<'a, Result<(), StoreError>> {
         LocalFutureObj::new(Box::new(async move {
-            warn!("add temp root not yet implemented for '{}'", path);
+            trace!("adding temp root {}", path);
+            let state_dir = self.get_state_dir()?;
+            // a running collector has read the temp roots already
+            let _lock = crate::gc::lock_gc(&state_dir, crate::gc::lock::LockType::Read).await?;
+            let mut temp_roots = self.temp_roots.lock().unwrap();
+            if temp_roots.is_none() {
+                *temp_roots = Some(crate::gc::TempRoots::new(&state_dir)?);
+            }
+            temp_roots
+                .as_mut()
+                .unwrap()
+                .add(&self.print_store_path(path))?;
             Ok(())
         }))
     }
@@ -557,6 +920,274 @@ impl WriteStore for Arc<LocalStore> {
         }))
     }
 
+    fn optimise_store<'a>(
+        &'a self,
+    ) -> LocalFutureObj<'a, Result<super::OptimiseStats, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut optimiser = super::Optimiser::new(&self.get_store_dir()?)?;
+            optimiser.load_inodes()?;
+
+            let paths = self.query_all_valid_paths().await?;
+            for path in &paths {
+                // keeps the garbage collector away from the path
+                self.add_temp_root(path).await?;
+                if !self.is_valid_path(path).await? {
+                    continue;
+                }
+                let printed = self.print_store_path(path);
+                debug!("optimising path '{}'", printed);
+                optimiser
+                    .optimise_path(std::path::Path::new(&printed))
+                    .await?;
+            }
+
+            info!(
+                "{} freed by hard-linking {} files",
+                optimiser.stats.bytes_freed, optimiser.stats.files_linked
+            );
+            Ok(optimiser.stats)
+        }))
+    }
+
+    fn verify_store<'a>(
+        &'a self,
+        check_contents: bool,
+        repair: bool,
+    ) -> LocalFutureObj<'a, Result<super::VerifyReport, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let store_dir = self.get_store_dir()?;
+            let mut on_disk = std::collections::HashSet::new();
+            for v in std::fs::read_dir(&store_dir)? {
+                on_disk.insert(v?.file_name().to_string_lossy().to_string());
+            }
+
+            // the database is not locked while the contents are hashed
+            let valid = {
+                let sqlite = self.sqlite.write().unwrap();
+                let mut stm = sqlite.prepare("SELECT path, hash FROM ValidPaths;")?;
+                let rows = stm.query_map(rusqlite::NO_PARAMS, |row| {
+                    Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+                })?;
+                let mut valid = Vec::new();
+                for v in rows {
+                    let (path, hash) = v?;
+                    valid.push((self.parse_store_path(&path)?, hash));
+                }
+                valid
+            };
+            debug!(
+                "verifying {} valid paths, {} entries in {}",
+                valid.len(),
+                on_disk.len(),
+                store_dir
+            );
+
+            let mut report = super::VerifyReport::default();
+            for (path, hash) in &valid {
+                if !on_disk.contains(&path.to_string()) {
+                    error!("path '{}' disappeared", self.print_store_path(path));
+                    report.missing.push(path.clone());
+                    continue;
+                }
+                if !check_contents {
+                    continue;
+                }
+
+                trace!("checking contents of {}", path);
+                let expected = super::Hash::from_sql_string(hash)?;
+                let (current, _) = crate::archive::hash_path(&self.print_store_path(path)).await?;
+                if current != expected {
+                    error!(
+                        "path '{}' was modified! expected hash '{}', got '{}'",
+                        self.print_store_path(path),
+                        expected,
+                        current
+                    );
+                    report.modified.push(path.clone());
+                }
+            }
+
+            if repair {
+                // there are no substituters to fetch a good copy from, and like the garbage
+                // collector no temp roots may be added while paths are deleted
+                let state_dir = self.get_state_dir()?;
+                let _lock =
+                    crate::gc::lock_gc(&state_dir, crate::gc::lock::LockType::Write).await?;
+                let bad: Vec<StorePath> = report
+                    .missing
+                    .iter()
+                    .chain(report.modified.iter())
+                    .cloned()
+                    .collect();
+                for path in bad {
+                    let referrers = self.query_referrers(&path).await?;
+                    if referrers.iter().any(|v| *v != path) {
+                        error!(
+                            "cannot repair '{}', it still has valid referrers",
+                            self.print_store_path(&path)
+                        );
+                        continue;
+                    }
+                    self.invalidate_paths(std::slice::from_ref(&path))?;
+                    crate::gc::delete_tree(std::path::Path::new(&self.print_store_path(&path)))?;
+                    report.invalidated.push(path);
+                }
+            }
+
+            Ok(report)
+        }))
+    }
+
+    fn collect_garbage<'a>(
+        &'a self,
+        options: &'a crate::gc::GCOptions,
+    ) -> LocalFutureObj<'a, Result<crate::gc::GCResults, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            use crate::gc::{roots, GCAction, GCResults};
+            use std::collections::{HashMap, HashSet};
+
+            let state_dir = self.get_state_dir()?;
+            let store_dir = self.get_store_dir()?;
+            // only one collector runs at a time, and no temp roots are added while it does
+            let _lock = crate::gc::lock_gc(&state_dir, crate::gc::lock::LockType::Write).await?;
+
+            let mut roots = roots::find_roots(&state_dir, &store_dir)?;
+            roots::find_temp_roots(&state_dir, &store_dir, &mut roots)?;
+            roots::find_runtime_roots(&store_dir, &mut roots);
+            debug!("found {} roots", roots.len());
+
+            let (references, edges) = self.query_gc_graph()?;
+            let mut live = HashSet::new();
+            let mut queue: Vec<StorePath> = roots.into_keys().collect();
+            while let Some(path) = queue.pop() {
+                if let Some(v) = edges.get(&path) {
+                    queue.extend(v.iter().filter(|v| !live.contains(*v)).cloned());
+                }
+                live.insert(path);
+            }
+
+            let mut dead: Vec<StorePath> = references
+                .keys()
+                .filter(|v| !live.contains(*v))
+                .cloned()
+                .collect();
+            // leftovers of interrupted builds and imports
+            for v in std::fs::read_dir(&store_dir)? {
+                let name = v?.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') || name.ends_with(".lock") {
+                    continue;
+                }
+                let path =
+                    match roots::to_store_path(&store_dir, &format!("{}/{}", store_dir, name)) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                if !references.contains_key(&path) && !live.contains(&path) {
+                    dead.push(path);
+                }
+            }
+            dead.sort();
+
+            let mut results = GCResults::default();
+            let to_delete = match options.action {
+                GCAction::ReturnLive => {
+                    results.paths = live
+                        .into_iter()
+                        .filter(|v| references.contains_key(v))
+                        .collect();
+                    results.paths.sort();
+                    return Ok(results);
+                }
+                GCAction::ReturnDead => {
+                    results.paths = dead;
+                    return Ok(results);
+                }
+                GCAction::DeleteDead => dead,
+                GCAction::DeleteSpecific => {
+                    let mut referrers: HashMap<&StorePath, Vec<&StorePath>> = HashMap::new();
+                    for (path, refs) in &references {
+                        for v in refs.iter().filter(|v| *v != path) {
+                            referrers.entry(v).or_default().push(path);
+                        }
+                    }
+
+                    // a dead path stays if one of its referrers is not deleted with it
+                    let dead: HashSet<&StorePath> = dead.iter().collect();
+                    let mut deletable: HashSet<&StorePath> = options
+                        .paths_to_delete
+                        .iter()
+                        .filter(|v| dead.contains(v))
+                        .collect();
+                    loop {
+                        let blocked: Vec<&StorePath> = deletable
+                            .iter()
+                            .filter(|v| {
+                                referrers
+                                    .get(*v)
+                                    .into_iter()
+                                    .flatten()
+                                    .any(|v| !deletable.contains(v))
+                            })
+                            .cloned()
+                            .collect();
+                        if blocked.is_empty() {
+                            break;
+                        }
+                        for v in blocked {
+                            deletable.remove(v);
+                        }
+                    }
+
+                    for v in &options.paths_to_delete {
+                        if !deletable.contains(v) && (live.contains(v) || dead.contains(v)) {
+                            return Err(StoreError::PathAlive {
+                                path: self.print_store_path(v),
+                            });
+                        }
+                    }
+                    let mut paths: Vec<StorePath> = deletable.into_iter().cloned().collect();
+                    paths.sort();
+                    paths
+                }
+            };
+
+            // referrers go first, so stopping early never leaves a dangling reference
+            let graph: References = to_delete
+                .iter()
+                .map(|v| (v.clone(), references.get(v).cloned().unwrap_or_default()))
+                .collect();
+            let mut order = crate::store::path::references_first(&to_delete, &graph);
+            order.reverse();
+
+            let mut batch = Vec::new();
+            let mut planned = 0;
+            for path in order {
+                if planned >= options.max_freed {
+                    info!("deleted more than {} bytes, stopping", options.max_freed);
+                    break;
+                }
+                let size =
+                    crate::gc::path_size(std::path::Path::new(&self.print_store_path(&path)))?;
+                planned += size;
+                batch.push((path, size));
+                if batch.len() == GC_BATCH_SIZE {
+                    self.delete_garbage(&batch, &references, &mut results)?;
+                    batch.clear();
+                }
+            }
+            self.delete_garbage(&batch, &references, &mut results)?;
+
+            results.bytes_freed += crate::gc::remove_unused_links(&store_dir)?;
+            info!(
+                "{} store paths deleted, {} bytes freed",
+                results.paths.len(),
+                results.bytes_freed
+            );
+
+            Ok(results)
+        }))
+    }
+
     fn box_clone_write(&self) -> Box<dyn WriteStore> {
         Box::new(self.clone())
     }
@@ -605,9 +1236,9 @@ impl ReadStore for Arc<LocalStore> {
                 let ultimate: bool = row.get::<usize, isize>(5).unwrap_or(0) != 0;
                 let sigs: Vec<String> = row
                     .get::<usize, String>(6)
-                    .map(|v| v.split(' ').map(|v| v.to_string()).collect())
+                    .map(|v| v.split_whitespace().map(|v| v.to_string()).collect())
                     .unwrap_or(Vec::new());
-                let ca: Option<String> = row.get::<usize, String>(7).ok();
+                let ca: Option<String> = row.get::<usize, String>(7).ok().filter(|v| !v.is_empty());
                 Ok(crate::store::ValidPathInfo {
                     path: path.clone(),
                     deriver,
@@ -704,6 +1335,211 @@ impl ReadStore for Arc<LocalStore> {
         }))
     }
 
+    fn query_valid_paths<'a>(
+        &'a self,
+        paths: &'a [StorePath],
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let printed: Vec<String> = paths.iter().map(|v| self.print_store_path(v)).collect();
+            let mut valid = std::collections::HashSet::new();
+
+            let sqlite = self.sqlite.write().unwrap();
+            // stay below the limit of bound parameters of older sqlite versions
+            for batch in printed.chunks(VALID_PATHS_BATCH) {
+                let sql = format!(
+                    "SELECT path FROM ValidPaths WHERE path IN ({});",
+                    vec!["?"; batch.len()].join(", ")
+                );
+                let mut stm = sqlite.prepare_cached(&sql)?;
+                let rows = stm.query_map(batch, |row| row.get::<usize, String>(0))?;
+                for v in rows {
+                    valid.insert(v?);
+                }
+            }
+
+            Ok(paths
+                .iter()
+                .zip(printed.iter())
+                .filter(|(_, printed)| valid.contains(*printed))
+                .map(|(v, _)| v.clone())
+                .collect())
+        }))
+    }
+
+    fn query_all_valid_paths<'a>(
+        &'a self,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let sqlite = self.sqlite.write().unwrap();
+            let mut stm = sqlite.prepare_cached("SELECT path FROM ValidPaths;")?;
+            let rows = stm.query_map(rusqlite::NO_PARAMS, |row| row.get::<usize, String>(0))?;
+
+            let mut paths = Vec::new();
+            for v in rows {
+                paths.push(self.parse_store_path(&v?)?);
+            }
+            Ok(paths)
+        }))
+    }
+
+    fn query_path_from_hash_part<'a>(
+        &'a self,
+        hash_part: &'a str,
+    ) -> LocalFutureObj<'a, Result<Option<StorePath>, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            if !super::path::is_valid_hash_part(hash_part) {
+                return Err(StoreError::BadHashPart {
+                    hash_part: hash_part.to_string(),
+                });
+            }
+
+            // the first path not sorting before the prefix, only a match if it has the prefix
+            let prefix = format!("{}/{}", self.get_store_dir()?, hash_part);
+            let sqlite = self.sqlite.write().unwrap();
+            let mut stm =
+                sqlite.prepare_cached("SELECT path FROM ValidPaths WHERE path >= (?) LIMIT 1;")?;
+            let mut rows = stm.query_map(&[&prefix], |row| row.get::<usize, String>(0))?;
+
+            match rows.next() {
+                Some(path) => {
+                    let path = path?;
+                    if path.starts_with(&prefix) {
+                        Ok(Some(self.parse_store_path(&path)?))
+                    } else {
+                        Ok(None)
+                    }
+                }
+                None => Ok(None),
+            }
+        }))
+    }
+
+    fn query_store_stats<'a>(
+        &'a self,
+    ) -> LocalFutureObj<'a, Result<super::StoreStats, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let sqlite = self.sqlite.write().unwrap();
+            let (valid_paths, nar_size) = sqlite.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(narSize), 0) FROM ValidPaths;",
+                rusqlite::NO_PARAMS,
+                |row| Ok((row.get::<usize, i64>(0)?, row.get::<usize, i64>(1)?)),
+            )?;
+            drop(sqlite);
+
+            let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+            let store_dir = std::ffi::CString::new(self.get_store_dir()?).unwrap();
+            if unsafe { libc::statvfs(store_dir.as_ptr(), stat.as_mut_ptr()) } != 0 {
+                return Err(StoreError::SysError {
+                    msg: format!(
+                        "getting info about the store file system: {}",
+                        self.get_store_dir()?
+                    ),
+                });
+            }
+            let stat = unsafe { stat.assume_init() };
+
+            // the field types of statvfs differ between platforms
+            #[allow(clippy::unnecessary_cast)]
+            Ok(super::StoreStats {
+                valid_paths: valid_paths as u64,
+                nar_size: nar_size as u64,
+                free_space: stat.f_bavail as u64 * stat.f_frsize as u64,
+            })
+        }))
+    }
+
+    fn query_references<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let sqlite = self.sqlite.write().unwrap();
+            // uses the primary key of Refs
+            let mut stm = sqlite.prepare_cached(
+                "SELECT path FROM Refs JOIN ValidPaths ON reference = id \
+                 WHERE referrer = (SELECT id FROM ValidPaths WHERE path = (?));",
+            )?;
+            let paths = stm.query_map(&[&self.print_store_path(path)], |row| {
+                row.get::<usize, String>(0)
+            })?;
+
+            let mut references = Vec::new();
+            for v in paths {
+                references.push(self.parse_store_path(&v?)?);
+            }
+            Ok(references)
+        }))
+    }
+
+    fn query_referrers<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let sqlite = self.sqlite.write().unwrap();
+            // uses IndexReference
+            let mut stm = sqlite.prepare_cached(
+                "SELECT path FROM Refs JOIN ValidPaths ON referrer = id \
+                 WHERE reference = (SELECT id FROM ValidPaths WHERE path = (?));",
+            )?;
+            let paths = stm.query_map(&[&self.print_store_path(path)], |row| {
+                row.get::<usize, String>(0)
+            })?;
+
+            let mut referrers = Vec::new();
+            for v in paths {
+                referrers.push(self.parse_store_path(&v?)?);
+            }
+            Ok(referrers)
+        }))
+    }
+
+    fn query_valid_derivers<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let sqlite = self.sqlite.write().unwrap();
+            // uses IndexDerivationOutputs
+            let mut stm = sqlite.prepare_cached(
+                "SELECT v.path FROM DerivationOutputs d JOIN ValidPaths v ON d.drv = v.id \
+                 WHERE d.path = (?);",
+            )?;
+            let paths = stm.query_map(&[&self.print_store_path(path)], |row| {
+                row.get::<usize, String>(0)
+            })?;
+
+            let mut derivers = Vec::new();
+            for v in paths {
+                derivers.push(self.parse_store_path(&v?)?);
+            }
+            Ok(derivers)
+        }))
+    }
+
+    fn query_derivation_output_map<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::OutputPathMap, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let sqlite = self.sqlite.write().unwrap();
+            let mut stm = sqlite.prepare_cached(
+                "SELECT id, path FROM DerivationOutputs \
+                 WHERE drv = (SELECT id FROM ValidPaths WHERE path = (?));",
+            )?;
+            let outputs = stm.query_map(&[&self.print_store_path(path)], |row| {
+                Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+            })?;
+
+            let mut map = super::path::OutputPathMap::new();
+            for v in outputs {
+                let (name, path) = v?;
+                map.insert(name, self.parse_store_path(&path)?);
+            }
+            Ok(map)
+        }))
+    }
+
     fn box_clone_read(&self) -> Box<dyn ReadStore> {
         Box::new(self.clone())
     }
@@ -724,6 +1560,84 @@ impl Store for Arc<LocalStore> {
 }
 
 // FIXME
+/// Release the locks on the outputs of a build and remove their files
+fn remove_locks(locks: Vec<(String, std::fs::File)>) {
+    for (lock, file) in locks {
+        // removed while locked, so nobody waits on a stale file
+        if let Err(e) = std::fs::remove_file(&lock) {
+            warn!("could not remove '{}': {}", lock, e);
+        }
+        drop(file);
+    }
+}
+
+/// Make the outputs of a finished build of `drv` store paths and register them,
+/// returns why the outputs are rejected instead
+async fn register_outputs(
+    store: &Arc<LocalStore>,
+    drv_path: &StorePath,
+    drv: &crate::build::derivation::Derivation,
+) -> Result<Option<String>, StoreError> {
+    use crate::build::local;
+
+    // outputs can only refer to the closure of the inputs and to each other
+    let mut candidates: Vec<StorePath> = drv.outputs.values().map(|v| v.path.clone()).collect();
+    let mut todo = drv.input_srcs.clone();
+    let mut seen = std::collections::HashSet::new();
+    while let Some(path) = todo.pop() {
+        if seen.insert(path.clone()) {
+            todo.extend(store.query_references(&path).await?);
+            candidates.push(path);
+        }
+    }
+
+    let mut infos = std::collections::HashMap::new();
+    for output in drv.outputs.values() {
+        let path = store.print_store_path(&output.path);
+        match std::fs::symlink_metadata(&path) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Some(format!(
+                    "builder for '{}' failed to produce output path '{}'",
+                    store.print_store_path(drv_path),
+                    path
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        local::canonicalise(std::path::Path::new(&path))?;
+
+        if let Some(expected) = &output.hash {
+            if let Some(actual) = local::check_fixed_output(&path, expected).await? {
+                return Ok(Some(format!(
+                    "hash mismatch in fixed-output derivation '{}': wanted '{}', got '{}'",
+                    path,
+                    expected,
+                    actual.to_sql_string()
+                )));
+            }
+        }
+
+        let (hash, size) = crate::archive::hash_path(&path).await?;
+        let mut info = ValidPathInfo::now(output.path.clone(), hash, size)?;
+        info.deriver = Some(drv_path.clone());
+        info.references = local::scan_references(&path, &candidates).await?;
+        info.ultimate = true;
+        infos.insert(output.path.clone(), info);
+    }
+
+    // an output has to be valid before the outputs referring to it
+    let references = infos
+        .iter()
+        .map(|(k, v)| (k.clone(), v.references.clone()))
+        .collect();
+    let outputs: Vec<StorePath> = infos.keys().cloned().collect();
+    for v in super::path::references_first(&outputs, &references) {
+        store.register_path(infos.remove(&v).unwrap()).await?;
+    }
+    Ok(None)
+}
+
 fn get_hash_part(path: &std::path::PathBuf) -> String {
     let filename = path.file_name().unwrap();
     let filename = filename.to_string_lossy();
@@ -732,6 +1646,7 @@ fn get_hash_part(path: &std::path::PathBuf) -> String {
     String::from(&filename[0..pos])
 }
 
+/// Sort `path` and everything needed to realise it into `state`, following input derivations
 fn do_path<'a>(
     path: StorePathWithOutputs,
     state: Arc<std::sync::Mutex<super::MissingInfo>>,
@@ -739,96 +1654,672 @@ fn do_path<'a>(
     substitute: bool,
 ) -> LocalFutureObj<'a, Result<(), StoreError>> {
     LocalFutureObj::new(Box::new(async move {
-        let mut state_l = state.lock().unwrap();
-        if state_l.done.contains(&path.path.name()) {
+        if !state.lock().unwrap().done.insert(path.to_string()) {
             return Ok(());
         }
-        state_l.done.push(path.path.name());
-        drop(state_l);
 
-        println!("working on {}", path.path.name());
+        trace!("working on {}", path);
 
-        if path.path.is_derivation() {
-            if !store.is_valid_path(&path.path).await? {
-                //insert into unknown
-                // TODO: we could try to substitute the drv
-                let mut state_l = state.lock().unwrap();
-                state_l.unknown.push(path.path.clone());
+        if !path.path.is_derivation() {
+            if store.is_valid_path(&path.path).await? {
                 return Ok(());
             }
-            let drv = crate::build::derivation::Derivation::from_path(&path.path, &store).await?;
-            let drv = crate::build::derivation::ParsedDerivation::new(path.path.clone(), drv)?;
-
-            // TODO:
-            /*
-            PathSet invalid;
-            for (auto & j : drv->outputs)
-                if (wantOutput(j.first, path.outputs)
-                    && !isValidPath(j.second.path))
-                    invalid.insert(printStorePath(j.second.path));
-            if (invalid.empty()) return;*/
-            let mut invalid = crate::store::path::StorePaths::new();
-            for (name, out) in &drv.derivation.outputs {
-                if path.outputs.contains(name) && !store.is_valid_path(&out.path).await? {
-                    trace!("adding {} to doto list", store.print_store_path(&out.path));
-                    invalid.push(out.path.clone());
-                }
-            }
-            if invalid.is_empty() {
-                return Ok(());
+            let info = if substitute {
+                store.query_substitutable_path_info(&path.path).await?
+            } else {
+                None
+            };
+            let info = match info {
+                Some(v) => v,
+                None => {
+                    state.lock().unwrap().unknown.push(path.path);
+                    return Ok(());
+                }
+            };
+
+            {
+                let mut state = state.lock().unwrap();
+                state.will_substitute.push(path.path);
+                state.download_size += info.download_size;
+                state.nar_size += info.nar_size;
             }
 
-            if substitute && drv.substitutes_allowed() {
-                for v in invalid {
-                    debug!("check for subst: {}", v);
-                    do_path(
-                        StorePathWithOutputs::new(v),
-                        state.clone(),
-                        store.clone(),
-                        substitute,
-                    )
-                    .await?;
-                    unimplemented!("qeue path");
-                    // https://source.kloenk.de/github.com/NixOS/nix@9223603908abaa62711296aa224e1bc3d7fb0a91/-/blob/src/libstore/misc.cc?utm_source=share#L151
+            for v in info.references {
+                do_path(
+                    StorePathWithOutputs::new(v),
+                    state.clone(),
+                    store.clone(),
+                    substitute,
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+
+        if !store.is_valid_path(&path.path).await? {
+            // TODO: we could try to substitute the drv
+            state.lock().unwrap().unknown.push(path.path);
+            return Ok(());
+        }
+        let drv = crate::build::derivation::Derivation::from_path(&path.path, &store).await?;
+        let drv = crate::build::derivation::ParsedDerivation::new(path.path.clone(), drv)?;
+
+        let mut invalid = crate::store::path::StorePaths::new();
+        for (name, out) in &drv.derivation.outputs {
+            if path.wants_output(name) && !store.is_valid_path(&out.path).await? {
+                invalid.push(out.path.clone());
+            }
+        }
+        if invalid.is_empty() {
+            return Ok(());
+        }
+        invalid.sort();
+
+        // outputs are only substituted if all of them can be, otherwise the derivation is built
+        let mut must_build = !substitute || !drv.substitutes_allowed();
+        if !must_build {
+            for v in &invalid {
+                if store.query_substitutable_path_info(v).await?.is_none() {
+                    debug!("no substitute for {}", v);
+                    must_build = true;
+                    break;
                 }
-            } else {
-                unimplemented!("no substitute enabled");
+            }
+        }
+
+        if must_build {
+            state.lock().unwrap().will_build.push(path.path.clone());
+            let mut inputs: Vec<_> = drv.derivation.inputs.iter().collect();
+            inputs.sort();
+            for (input, outputs) in inputs {
+                do_path(
+                    StorePathWithOutputs::new_with_outputs(input.clone(), outputs.clone()),
+                    state.clone(),
+                    store.clone(),
+                    substitute,
+                )
+                .await?;
             }
         } else {
-            warn!(
-                "non derivation path: {}",
-                store.print_store_path(&path.path)
-            );
-            /*
-            if (isValidPath(path.path)) return;
+            for v in invalid {
+                do_path(
+                    StorePathWithOutputs::new(v),
+                    state.clone(),
+                    store.clone(),
+                    substitute,
+                )
+                .await?;
+            }
+        }
 
-            SubstitutablePathInfos infos;
-            querySubstitutablePathInfos({path.path}, infos);
+        Ok(())
+    }))
+}
 
-            if (infos.empty()) {
-                auto state(state_.lock());
-                state->unknown.insert(path.path);
-                return;
-            }
+#[cfg(test)]
+pub(crate) mod test {
+    use super::LocalStore;
+    use crate::store::{BuildStore, ReadStore, StorePath, WriteStore};
+    use std::sync::Arc;
+
+    /// Tables of the Nix database used by `LocalStore`
+    pub const SCHEMA: &str = "
+        CREATE TABLE ValidPaths (
+            id               INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            path             TEXT UNIQUE NOT NULL,
+            hash             TEXT NOT NULL,
+            registrationTime INTEGER NOT NULL,
+            deriver          TEXT,
+            narSize          INTEGER,
+            ultimate         INTEGER,
+            sigs             TEXT,
+            ca               TEXT
+        );
+        CREATE TABLE Refs (
+            referrer  INTEGER NOT NULL,
+            reference INTEGER NOT NULL,
+            PRIMARY KEY (referrer, reference),
+            FOREIGN KEY (referrer) REFERENCES ValidPaths(id) ON DELETE CASCADE,
+            FOREIGN KEY (reference) REFERENCES ValidPaths(id) ON DELETE RESTRICT
+        );
+        CREATE INDEX IndexReferrer ON Refs(referrer);
+        CREATE INDEX IndexReference ON Refs(reference);
+        CREATE TABLE DerivationOutputs (
+            drv  INTEGER NOT NULL,
+            id   TEXT NOT NULL,
+            path TEXT NOT NULL,
+            PRIMARY KEY (drv, id),
+            FOREIGN KEY (drv) REFERENCES ValidPaths(id) ON DELETE CASCADE
+        );
+        CREATE INDEX IndexDerivationOutputs ON DerivationOutputs(path);
+    ";
+
+    /// Open an empty store with the Nix schema in a fresh directory
+    pub async fn open_test_store(name: &str) -> Arc<LocalStore> {
+        open_test_store_with(name, std::collections::HashMap::new()).await
+    }
 
-            auto info = infos.find(path.path);
-            assert(info != infos.end());
+    /// Like `open_test_store`, with store `params` instead of the global config
+    pub async fn open_test_store_with(
+        name: &str,
+        params: std::collections::HashMap<String, crate::store::Param>,
+    ) -> Arc<LocalStore> {
+        let base = format!(
+            "{}/nix-test-local-store-{}-{}/",
+            std::env::temp_dir().display(),
+            name,
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(format!("{}store", base)).unwrap();
+        std::fs::create_dir_all(format!("{}var/nix/db", base)).unwrap();
+        rusqlite::Connection::open(format!("{}var/nix/db/db.sqlite", base))
+            .unwrap()
+            .execute_batch(SCHEMA)
+            .unwrap();
+
+        LocalStore::open_store(&base, params).await.unwrap()
+    }
 
-            {
-                auto state(state_.lock());
-                state->willSubstitute.insert(path.path);
-                state->downloadSize += info->second.downloadSize;
-                state->narSize += info->second.narSize;
+    /// Insert a valid path with the given references directly into the database
+    pub fn insert_path(store: &Arc<LocalStore>, name: &str, references: &[&str]) -> StorePath {
+        let path = StorePath::new(name).unwrap();
+        let sqlite = store.sqlite.write().unwrap();
+        sqlite
+            .execute(
+                "INSERT INTO ValidPaths (path, hash, registrationTime, narSize) VALUES (?, ?, 0, 0);",
+                &[
+                    &format!("{}/{}", store.get_store_dir(), name),
+                    &format!("sha256:{}", "0".repeat(64)),
+                ],
+            )
+            .unwrap();
+        for v in references {
+            sqlite
+                .execute(
+                    "INSERT INTO Refs (referrer, reference) VALUES \
+                     ((SELECT id FROM ValidPaths WHERE path = ?), (SELECT id FROM ValidPaths WHERE path = ?));",
+                    &[
+                        &format!("{}/{}", store.get_store_dir(), name),
+                        &format!("{}/{}", store.get_store_dir(), v),
+                    ],
+                )
+                .unwrap();
+        }
+        path
+    }
+
+    pub fn remove_test_store(store: &Arc<LocalStore>) {
+        std::fs::remove_dir_all(&store.base_dir).unwrap();
+    }
+
+    const FOO: &str = "ffffffffffffffffffffffffffffffff-foo";
+    const BAR: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bar";
+    const BAZ: &str = "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-baz";
+
+    #[tokio::test]
+    async fn references() {
+        let store = open_test_store("references").await;
+        let baz = insert_path(&store, BAZ, &[]);
+        let bar = insert_path(&store, BAR, &[BAZ]);
+        let foo = insert_path(&store, FOO, &[BAR, BAZ, FOO]);
+
+        let mut refs = store.query_references(&foo).await.unwrap();
+        refs.sort();
+        assert_eq!(refs, vec![bar.clone(), foo.clone(), baz.clone()]);
+        assert!(store.query_references(&baz).await.unwrap().is_empty());
+
+        let mut referrers = store.query_referrers(&baz).await.unwrap();
+        referrers.sort();
+        assert_eq!(referrers, vec![bar, foo.clone()]);
+        assert_eq!(store.query_referrers(&foo).await.unwrap(), vec![foo]);
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn valid_paths() {
+        let store = open_test_store("valid-paths").await;
+        let baz = insert_path(&store, BAZ, &[]);
+        let foo = insert_path(&store, FOO, &[]);
+        let bar = StorePath::new(BAR).unwrap();
+
+        // more paths than fit into one batch
+        let mut paths = vec![bar.clone(); super::VALID_PATHS_BATCH];
+        paths.push(foo.clone());
+        paths.push(bar);
+        paths.push(baz.clone());
+        assert_eq!(
+            store.query_valid_paths(&paths).await.unwrap(),
+            vec![foo.clone(), baz.clone()]
+        );
+
+        let mut all = store.query_all_valid_paths().await.unwrap();
+        all.sort();
+        assert_eq!(all, vec![foo, baz]);
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn path_from_hash_part() {
+        let store = open_test_store("hash-part").await;
+        let foo = insert_path(&store, FOO, &[]);
+        insert_path(&store, BAZ, &[]);
+
+        let query = |v: &'static str| store.query_path_from_hash_part(v);
+        assert_eq!(query(&FOO[..32]).await.unwrap(), Some(foo));
+        // bar sorts before foo, the range query finds foo
+        assert_eq!(query(&BAR[..32]).await.unwrap(), None);
+        // baz sorts right after the prefix, but does not start with it
+        assert_eq!(
+            query("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzy").await.unwrap(),
+            None
+        );
+        assert!(query("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz").await.is_err());
+        assert!(query("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee").await.is_err());
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn derivation_outputs() {
+        let store = open_test_store("derivation-outputs").await;
+        let dir = store.get_store_dir();
+        let drv = format!(
+            r#"Derive([("dev","{}/{}","",""),("out","{}/{}","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#,
+            dir, BAR, dir, FOO
+        );
+        let info = store
+            .add_text_to_store("foo.drv", drv.as_bytes(), &Vec::new(), false)
+            .await
+            .unwrap();
+
+        let map = store.query_derivation_output_map(&info.path).await.unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map["out"], StorePath::new(FOO).unwrap());
+        assert_eq!(map["dev"], StorePath::new(BAR).unwrap());
+        assert_eq!(
+            store.query_derivation_outputs(&info.path).await.unwrap(),
+            vec![StorePath::new(BAR).unwrap(), StorePath::new(FOO).unwrap()]
+        );
+
+        // the outputs do not have to be valid
+        let foo = StorePath::new(FOO).unwrap();
+        assert_eq!(
+            store.query_valid_derivers(&foo).await.unwrap(),
+            vec![info.path.clone()]
+        );
+        let baz = insert_path(&store, BAZ, &[]);
+        assert!(store.query_valid_derivers(&baz).await.unwrap().is_empty());
+        assert!(store
+            .query_derivation_output_map(&baz)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // derivations this store can not parse are still registered
+        let info = store
+            .add_text_to_store("bar.drv", b"Derive(", &Vec::new(), false)
+            .await
+            .unwrap();
+        assert!(store.is_valid_path(&info.path).await.unwrap());
+        assert!(store
+            .query_derivation_output_map(&info.path)
+            .await
+            .unwrap()
+            .is_empty());
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn query_missing() {
+        use crate::store::path::StorePathWithOutputs;
+
+        let store = open_test_store("query-missing").await;
+        let dir = store.get_store_dir();
+        let dep = format!(
+            r#"Derive([("out","{}/{}","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#,
+            dir, BAR
+        );
+        let dep = store
+            .add_text_to_store("bar.drv", dep.as_bytes(), &Vec::new(), false)
+            .await
+            .unwrap()
+            .path;
+        let drv = format!(
+            r#"Derive([("out","{}/{}","","")],[("{}/{}",["out"])],[],"x86_64-linux","/bin/sh",[],[])"#,
+            dir, FOO, dir, dep
+        );
+        let drv = store
+            .add_text_to_store("foo.drv", drv.as_bytes(), &Vec::new(), false)
+            .await
+            .unwrap()
+            .path;
+        let unknown_drv = StorePath::new(&format!("{}.drv", BAZ)).unwrap();
+
+        let paths = vec![
+            StorePathWithOutputs::new_with_outputs(drv.clone(), vec!["out".to_string()]),
+            StorePathWithOutputs::new(StorePath::new(BAZ).unwrap()),
+            StorePathWithOutputs::new(unknown_drv.clone()),
+        ];
+        let missing = store.query_missing(&paths).await.unwrap();
+        let mut will_build = vec![drv.clone(), dep.clone()];
+        will_build.sort();
+        assert_eq!(missing.will_build, will_build);
+        assert!(missing.will_substitute.is_empty());
+        let mut unknown = vec![StorePath::new(BAZ).unwrap(), unknown_drv];
+        unknown.sort();
+        assert_eq!(missing.unknown, unknown);
+        assert_eq!((missing.download_size, missing.nar_size), (0, 0));
+
+        // valid outputs do not need their inputs
+        insert_path(&store, FOO, &[]);
+        let missing = store.query_missing(&paths[..1].to_vec()).await.unwrap();
+        assert!(missing.will_build.is_empty());
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn collect_garbage() {
+        use crate::gc::{GCAction, GCOptions};
+        use std::os::unix::fs::PermissionsExt;
+
+        const JUNK: &str = "cccccccccccccccccccccccccccccccc-junk";
+        const TEMP: &str = "dddddddddddddddddddddddddddddddd-temp";
+        let store = open_test_store("collect-garbage").await;
+        let dir = store.get_store_dir();
+        for v in &[FOO, BAR, BAZ, JUNK, TEMP] {
+            let path = format!("{}/{}", dir, v);
+            std::fs::create_dir(&path).unwrap();
+            std::fs::write(format!("{}/file", path), v).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o555)).unwrap();
+        }
+        let bar = insert_path(&store, BAR, &[]);
+        let foo = insert_path(&store, FOO, &[BAR]);
+        let baz = insert_path(&store, BAZ, &[]);
+        let temp = insert_path(&store, TEMP, &[]);
+        let junk = StorePath::new(JUNK).unwrap();
+        std::fs::create_dir_all(format!("{}gcroots", store.get_state_dir())).unwrap();
+        std::os::unix::fs::symlink(
+            format!("{}/{}", dir, FOO),
+            format!("{}gcroots/foo", store.get_state_dir()),
+        )
+        .unwrap();
+        // another connection, with its own temp roots
+        let other = LocalStore::open_store(&store.base_dir, std::collections::HashMap::new())
+            .await
+            .unwrap();
+        other.add_temp_root(&temp).await.unwrap();
+
+        let gc = |action, paths: Vec<StorePath>, max_freed| {
+            let store = store.clone();
+            async move {
+                let mut options = GCOptions::new(action);
+                options.paths_to_delete = paths;
+                options.max_freed = max_freed;
+                store.collect_garbage(&options).await
             }
+        };
 
-            for (auto & ref : info->second.references)
-                pool.enqueue(std::bind(doPath, StorePathWithOutputs { ref }));
-                */
+        let live = gc(GCAction::ReturnLive, vec![], u64::MAX).await.unwrap();
+        assert_eq!(live.paths, vec![bar.clone(), temp.clone(), foo.clone()]);
+        let dead = gc(GCAction::ReturnDead, vec![], u64::MAX).await.unwrap();
+        assert_eq!(dead.paths, vec![junk.clone(), baz.clone()]);
+
+        assert!(gc(GCAction::DeleteSpecific, vec![bar.clone()], u64::MAX)
+            .await
+            .is_err());
+        let results = gc(GCAction::DeleteDead, vec![], 0).await.unwrap();
+        assert!(results.paths.is_empty());
+
+        let results = gc(GCAction::DeleteSpecific, vec![baz.clone()], u64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(results.paths, vec![baz.clone()]);
+        assert!(results.bytes_freed > 0);
+        assert!(!store.is_valid_path(&baz).await.unwrap());
+        assert!(!std::path::Path::new(&format!("{}/{}", dir, BAZ)).exists());
+
+        let results = gc(GCAction::DeleteDead, vec![], u64::MAX).await.unwrap();
+        assert_eq!(results.paths, vec![junk]);
+        assert!(store.is_valid_path(&foo).await.unwrap());
+        assert!(std::path::Path::new(&format!("{}/{}", dir, BAR)).exists());
+
+        // temp roots end with the connection which added them
+        drop(other);
+        let results = gc(GCAction::DeleteDead, vec![], u64::MAX).await.unwrap();
+        assert_eq!(results.paths, vec![temp]);
+
+        for v in &[FOO, BAR] {
+            let path = format!("{}/{}", dir, v);
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
         }
+        remove_test_store(&store);
+    }
 
-        /*return Err(StoreError::Unimplemented {
-            msg: "unimplemented".to_string(),
-        });*/
-        Ok(())
-    }))
+    #[tokio::test]
+    async fn temp_root_during_gc() {
+        use crate::gc::lock::LockType;
+        use std::time::Duration;
+
+        let store = open_test_store("temp-root-during-gc").await;
+        let foo = StorePath::new(FOO).unwrap();
+
+        // a collector is running
+        let lock = crate::gc::lock_gc(&store.get_state_dir(), LockType::Write)
+            .await
+            .unwrap();
+        let add = store.add_temp_root(&foo);
+        futures::pin_mut!(add);
+        assert!(tokio::time::timeout(Duration::from_millis(300), &mut add)
+            .await
+            .is_err());
+
+        drop(lock);
+        add.await.unwrap();
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn build_derivation() {
+        use crate::build::derivation::{Derivation, DerivationOutput};
+        use crate::store::{BuildMode, BuildOptions, BuildStatus};
+
+        let store = open_test_store("build-derivation").await;
+        let drv_path = StorePath::new(&format!("{}.drv", BAZ)).unwrap();
+        let out = format!("{}/{}", store.get_store_dir(), FOO);
+        let bar = format!("{}/{}", store.get_store_dir(), BAR);
+        let mut drv = Derivation::new();
+        drv.outputs.insert(
+            "out".to_string(),
+            DerivationOutput {
+                path: StorePath::new(FOO).unwrap(),
+                hash: None,
+            },
+        );
+        drv.input_srcs.push(StorePath::new(BAR).unwrap());
+        drv.platform = crate::CONFIG.read().unwrap().system.clone();
+        drv.builder = "/bin/sh".to_string();
+        drv.args = vec!["-c".to_string(), "echo $bar > $out; exit 3".to_string()];
+        drv.env.insert("out".to_string(), out.clone());
+        drv.env.insert("bar".to_string(), bar.clone());
+
+        let result = store
+            .build_derivation(&drv_path, &drv, BuildMode::Normal, &BuildOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.status, BuildStatus::DependencyFailed);
+        assert!(result.error_msg.contains(BAR));
+
+        insert_path(&store, BAR, &[]);
+        let result = store
+            .build_derivation(&drv_path, &drv, BuildMode::Check, &BuildOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.status, BuildStatus::MiscFailure);
+
+        // the partial output is removed
+        let result = store
+            .build_derivation(&drv_path, &drv, BuildMode::Normal, &BuildOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.status, BuildStatus::PermanentFailure);
+        assert!(result.error_msg.contains("exit code 3"));
+        assert!(!std::path::Path::new(&out).exists());
+
+        drv.args[1] = "echo $bar > $out".to_string();
+        let result = store
+            .build_derivation(&drv_path, &drv, BuildMode::Normal, &BuildOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.status, BuildStatus::Built, "{}", result.error_msg);
+        assert!(result.start_time <= result.stop_time);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), format!("{}\n", bar));
+        let foo = StorePath::new(FOO).unwrap();
+        assert!(store.is_valid_path(&foo).await.unwrap());
+        assert_eq!(
+            store.query_references(&foo).await.unwrap(),
+            vec![StorePath::new(BAR).unwrap()]
+        );
+
+        let result = store
+            .build_derivation(&drv_path, &drv, BuildMode::Normal, &BuildOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(result.status, BuildStatus::AlreadyValid);
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn auto_optimise() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let mut params = std::collections::HashMap::new();
+        params.insert(
+            "auto-optimise-store".to_string(),
+            crate::store::Param::Bool(true),
+        );
+        let store = open_test_store_with("auto-optimise", params).await;
+        let hash = crate::store::Hash::from_sha256_vec(&[0; 32]).unwrap();
+
+        for v in &[FOO, BAR] {
+            let file = format!("{}/{}", store.get_store_dir(), v);
+            std::fs::write(&file, "same").unwrap();
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o444)).unwrap();
+            let info =
+                crate::store::ValidPathInfo::now(StorePath::new(v).unwrap(), hash.clone(), 4)
+                    .unwrap();
+            store.register_path(info).await.unwrap();
+        }
+        // missing on disk, so it can not be optimised
+        let baz = StorePath::new(BAZ).unwrap();
+        let info = crate::store::ValidPathInfo::now(baz.clone(), hash, 4).unwrap();
+        let registered = store.register_path(info).await;
+
+        assert!(registered.is_ok());
+        assert!(store.is_valid_path(&baz).await.unwrap());
+        let ino = |v: &str| {
+            std::fs::metadata(format!("{}/{}", store.get_store_dir(), v))
+                .unwrap()
+                .ino()
+        };
+        assert_eq!(ino(FOO), ino(BAR));
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn signatures() {
+        let store = open_test_store("signatures").await;
+        let foo = StorePath::new(FOO).unwrap();
+
+        let mut info = crate::store::ValidPathInfo::now(
+            foo.clone(),
+            crate::store::Hash::from_sha256_vec(&[0; 32]).unwrap(),
+            120,
+        )
+        .unwrap();
+        info.sigs = vec!["cache-1:aaaa".to_string()];
+        info.ca = Some("text:sha256:0000".to_string());
+        store.register_path(info).await.unwrap();
+
+        let info = store.query_path_info(&foo).await.unwrap();
+        assert_eq!(info.sigs, vec!["cache-1:aaaa"]);
+        assert_eq!(info.ca.as_deref(), Some("text:sha256:0000"));
+
+        let sigs = vec![
+            "cache-2:bbbb".to_string(),
+            "cache-1:aaaa".to_string(),
+            "cache-2:bbbb".to_string(),
+        ];
+        store.add_signatures(&foo, &sigs).await.unwrap();
+        assert_eq!(
+            store.query_path_info(&foo).await.unwrap().sigs,
+            vec!["cache-1:aaaa", "cache-2:bbbb"]
+        );
+
+        let bar = StorePath::new(BAR).unwrap();
+        assert!(store.add_signatures(&bar, &sigs).await.is_err());
+
+        // paths without signatures or content address
+        let baz = insert_path(&store, BAZ, &[]);
+        let info = store.query_path_info(&baz).await.unwrap();
+        assert!(info.sigs.is_empty());
+        assert_eq!(info.ca, None);
+
+        remove_test_store(&store);
+    }
+
+    #[tokio::test]
+    async fn verify_store() {
+        let store = open_test_store("verify").await;
+        let dir = store.get_store_dir();
+
+        let info = store
+            .add_text_to_store("good", b"good", &Vec::new(), false)
+            .await
+            .unwrap();
+        let modified = store
+            .add_text_to_store("modified", b"original", &Vec::new(), false)
+            .await
+            .unwrap();
+        std::fs::remove_file(format!("{}/{}", dir, modified.path)).unwrap();
+        std::fs::write(format!("{}/{}", dir, modified.path), "changed").unwrap();
+        // missing, foo still refers to it
+        insert_path(&store, BAR, &[]);
+        insert_path(&store, FOO, &[BAR]);
+        std::fs::write(format!("{}/{}", dir, FOO), "").unwrap();
+        let bar = StorePath::new(BAR).unwrap();
+        let foo = StorePath::new(FOO).unwrap();
+
+        let report = store.verify_store(false, false).await.unwrap();
+        assert_eq!(report.missing, vec![bar.clone()]);
+        assert!(report.modified.is_empty());
+        assert!(report.errors());
+
+        // foo has the zero hash in the database
+        let report = store.verify_store(true, true).await.unwrap();
+        assert_eq!(report.missing, vec![bar.clone()]);
+        let mut bad = report.modified.clone();
+        bad.sort();
+        let mut expected = vec![modified.path.clone(), foo.clone()];
+        expected.sort();
+        assert_eq!(bad, expected);
+        // bar is still referred to by foo when it is checked
+        assert_eq!(report.invalidated, vec![modified.path.clone(), foo]);
+        assert!(report.errors());
+        assert!(store.is_valid_path(&info.path).await.unwrap());
+        assert!(!store.is_valid_path(&modified.path).await.unwrap());
+        assert!(!std::path::Path::new(&format!("{}/{}", dir, modified.path)).exists());
+
+        let report = store.verify_store(true, true).await.unwrap();
+        assert_eq!(report.invalidated, vec![bar]);
+        assert!(!report.errors());
+
+        remove_test_store(&store);
+    }
 }
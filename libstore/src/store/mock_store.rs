@@ -6,7 +6,10 @@ use std::boxed::Box;
 
 use std::sync::{Arc, Mutex};
 
-use super::{ReadStore, Store, StoreError, StorePath, ValidPathInfo, WriteStore};
+use super::{
+    BuildStore, MissingInfo, ReadStore, Store, StoreError, StorePath, StoreStats, ValidPathInfo,
+    WriteStore,
+};
 
 use std::collections::HashMap;
 
@@ -23,6 +26,8 @@ pub struct MockStore {
     files: Arc<Mutex<HashMap<String, File>>>,
     symlinks: Arc<Mutex<HashMap<String, String>>>,
     dirs: Arc<Mutex<Vec<String>>>,
+    /// Valid paths by base name
+    infos: Arc<Mutex<HashMap<String, ValidPathInfo>>>,
 }
 
 impl MockStore {
@@ -31,9 +36,16 @@ impl MockStore {
             files: Arc::new(Mutex::new(HashMap::new())),
             symlinks: Arc::new(Mutex::new(HashMap::new())),
             dirs: Arc::new(Mutex::new(Vec::new())),
+            infos: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// Make `info.path` a valid path
+    pub fn add_path_info(&self, info: ValidPathInfo) {
+        let mut infos = self.infos.lock().unwrap();
+        infos.insert(info.path.to_string(), info);
+    }
+
     pub fn file_exists(&self, path: &str) -> bool {
         let files = self.files.lock().unwrap();
         files.get(path).is_some()
@@ -118,18 +130,63 @@ impl WriteStore for Arc<MockStore> {
         unimplemented!()
     }
 
+    fn optimise_store<'a>(
+        &'a self,
+    ) -> LocalFutureObj<'a, Result<super::OptimiseStats, StoreError>> {
+        unimplemented!()
+    }
+
+    fn verify_store<'a>(
+        &'a self,
+        _check_contents: bool,
+        _repair: bool,
+    ) -> LocalFutureObj<'a, Result<super::VerifyReport, StoreError>> {
+        unimplemented!()
+    }
+
+    fn collect_garbage<'a>(
+        &'a self,
+        options: &'a crate::gc::GCOptions,
+    ) -> LocalFutureObj<'a, Result<crate::gc::GCResults, StoreError>> {
+        unimplemented!()
+    }
+
     fn register_path<'a>(
         &'a self,
         info: ValidPathInfo,
     ) -> LocalFutureObj<'a, Result<ValidPathInfo, StoreError>> {
-        unimplemented!()
+        LocalFutureObj::new(Box::new(async move {
+            self.add_path_info(info.clone());
+            Ok(info)
+        }))
+    }
+
+    fn add_signatures<'a>(
+        &'a self,
+        path: &'a StorePath,
+        sigs: &'a [String],
+    ) -> LocalFutureObj<'a, Result<(), StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut infos = self.infos.lock().unwrap();
+            let info = infos
+                .get_mut(&path.to_string())
+                .ok_or_else(|| StoreError::InvalidPath {
+                    path: path.to_string(),
+                })?;
+            for v in sigs {
+                if !info.sigs.contains(v) {
+                    info.sigs.push(v.clone());
+                }
+            }
+            Ok(())
+        }))
     }
 
     fn add_temp_root<'a>(
         &'a self,
         path: &'a StorePath,
     ) -> LocalFutureObj<'a, Result<(), StoreError>> {
-        unimplemented!()
+        LocalFutureObj::new(Box::new(async { Ok(()) }))
     }
 
     fn add_text_to_store<'a>(
@@ -162,21 +219,146 @@ impl ReadStore for Arc<MockStore> {
         &'a self,
         path: &'a StorePath,
     ) -> LocalFutureObj<'a, Result<ValidPathInfo, StoreError>> {
-        unimplemented!()
+        LocalFutureObj::new(Box::new(async move {
+            let infos = self.infos.lock().unwrap();
+            infos
+                .get(&path.to_string())
+                .cloned()
+                .ok_or_else(|| StoreError::NotInStore {
+                    path: path.to_string(),
+                })
+        }))
     }
 
     fn is_valid_path<'a>(
         &'a self,
         path: &'a StorePath,
     ) -> LocalFutureObj<'a, Result<bool, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            Ok(self.infos.lock().unwrap().contains_key(&path.to_string()))
+        }))
+    }
+
+    fn query_all_valid_paths<'a>(
+        &'a self,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let infos = self.infos.lock().unwrap();
+            let mut paths: super::path::StorePaths =
+                infos.values().map(|v| v.path.clone()).collect();
+            paths.sort();
+            Ok(paths)
+        }))
+    }
+
+    fn query_path_from_hash_part<'a>(
+        &'a self,
+        hash_part: &'a str,
+    ) -> LocalFutureObj<'a, Result<Option<StorePath>, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let infos = self.infos.lock().unwrap();
+            Ok(infos
+                .values()
+                .find(|v| v.path.hash_part() == hash_part)
+                .map(|v| v.path.clone()))
+        }))
+    }
+
+    fn query_store_stats<'a>(&'a self) -> LocalFutureObj<'a, Result<StoreStats, StoreError>> {
         unimplemented!()
     }
 
+    fn query_references<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            Ok(self.query_path_info(path).await?.references)
+        }))
+    }
+
+    fn query_referrers<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let infos = self.infos.lock().unwrap();
+            let mut referrers: super::path::StorePaths = infos
+                .values()
+                .filter(|v| v.references.contains(path))
+                .map(|v| v.path.clone())
+                .collect();
+            referrers.sort();
+            Ok(referrers)
+        }))
+    }
+
+    fn query_valid_derivers<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let infos = self.infos.lock().unwrap();
+            Ok(infos
+                .get(&path.to_string())
+                .and_then(|v| v.deriver.clone())
+                .filter(|v| infos.contains_key(&v.to_string()))
+                .into_iter()
+                .collect())
+        }))
+    }
+
+    /// The valid paths with `path` as deriver, named like the paths
+    fn query_derivation_output_map<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<super::path::OutputPathMap, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let infos = self.infos.lock().unwrap();
+            Ok(infos
+                .values()
+                .filter(|v| v.deriver.as_ref() == Some(path))
+                .map(|v| (v.path.name(), v.path.clone()))
+                .collect())
+        }))
+    }
+
     fn box_clone_read(&self) -> Box<dyn ReadStore> {
         Box::new(self.clone())
     }
 }
 
+impl BuildStore for Arc<MockStore> {
+    fn build_paths<'a>(
+        &'a self,
+        drvs: Vec<super::path::StorePathWithOutputs>,
+        mode: u8,
+    ) -> LocalFutureObj<'a, Result<(), StoreError>> {
+        unimplemented!()
+    }
+
+    fn query_missing<'a>(
+        &'a self,
+        paths: &'a Vec<super::path::StorePathWithOutputs>,
+    ) -> LocalFutureObj<'a, Result<MissingInfo, StoreError>> {
+        unimplemented!()
+    }
+
+    fn build_derivation<'a>(
+        &'a self,
+        _drv_path: &'a StorePath,
+        _drv: &'a crate::build::derivation::Derivation,
+        _mode: super::BuildMode,
+        _options: &'a super::BuildOptions,
+    ) -> LocalFutureObj<'a, Result<super::BuildResult, StoreError>> {
+        unimplemented!()
+    }
+
+    fn box_clone_build(&self) -> Box<dyn BuildStore> {
+        Box::new(self.clone())
+    }
+}
+
 impl Store for Arc<MockStore> {
     fn get_store_dir<'a>(&'a self) -> Result<String, StoreError> {
         Ok("/nix/store".to_string())
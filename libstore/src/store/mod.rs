@@ -29,9 +29,12 @@ pub use valid_path::ValidPathInfo;
 mod hash;
 pub use hash::Hash;
 
+mod optimise;
+pub use optimise::{OptimiseStats, Optimiser};
+
 #[derive(Debug)]
 pub struct MissingInfo {
-    pub done: Vec<String>,
+    pub done: std::collections::HashSet<String>,
 
     pub will_build: path::StorePaths,
     pub will_substitute: path::StorePaths,
@@ -50,11 +53,139 @@ impl MissingInfo {
             will_substitute: Vec::new(),
             unknown: Vec::new(),
 
-            done: Vec::new(),
+            done: std::collections::HashSet::new(),
+        }
+    }
+}
+
+/// Information a substituter has about a path it can provide
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubstitutablePathInfo {
+    pub deriver: Option<StorePath>,
+    pub references: path::StorePaths,
+    /// Size of the compressed NAR
+    pub download_size: u64,
+    pub nar_size: u64,
+}
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStatus {
+    Built = 0,
+    Substituted = 1,
+    AlreadyValid = 2,
+    PermanentFailure = 3,
+    InputRejected = 4,
+    OutputRejected = 5,
+    TransientFailure = 6,
+    CachedFailure = 7,
+    TimedOut = 8,
+    MiscFailure = 9,
+    DependencyFailed = 10,
+    LogLimitExceeded = 11,
+    NotDeterministic = 12,
+}
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildMode {
+    Normal = 0,
+    /// Rebuild outputs which are corrupted
+    Repair = 1,
+    /// Rebuild valid outputs and compare the results
+    Check = 2,
+}
+
+impl std::convert::TryFrom<u64> for BuildMode {
+    type Error = StoreError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(BuildMode::Normal),
+            1 => Ok(BuildMode::Repair),
+            2 => Ok(BuildMode::Check),
+            _ => Err(StoreError::InvalidBuildMode { mode: value }),
+        }
+    }
+}
+
+/// Limits of a single build in seconds, 0 means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuildOptions {
+    /// Time the builder may go without writing to its log
+    pub max_silent_time: u64,
+    pub timeout: u64,
+}
+
+impl BuildOptions {
+    /// The `max-silent-time` and `timeout` settings
+    pub fn from_config() -> Self {
+        let config = crate::CONFIG.read().unwrap();
+        Self {
+            max_silent_time: config.max_silent_time as u64,
+            timeout: config.timeout as u64,
         }
     }
 }
 
+/// Outcome of `BuildStore::build_derivation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildResult {
+    pub status: BuildStatus,
+    pub error_msg: String,
+    /// How many times the derivation was built, more than once with `--repeat`
+    pub times_built: u32,
+    pub is_non_deterministic: bool,
+    /// Seconds since the epoch
+    pub start_time: i64,
+    pub stop_time: i64,
+}
+
+impl BuildResult {
+    pub fn new(status: BuildStatus, error_msg: &str) -> Self {
+        Self {
+            status,
+            error_msg: error_msg.to_string(),
+            times_built: 0,
+            is_non_deterministic: false,
+            start_time: 0,
+            stop_time: 0,
+        }
+    }
+}
+
+/// Size of the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of valid paths
+    pub valid_paths: u64,
+    /// Sum of the nar sizes of all valid paths
+    pub nar_size: u64,
+    /// Free space on the file system holding the store
+    pub free_space: u64,
+}
+
+/// Problems found by `WriteStore::verify_store`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Valid paths which do not exist on disk
+    pub missing: path::StorePaths,
+    /// Valid paths whose NAR hash does not match the database
+    pub modified: path::StorePaths,
+    /// Bad paths removed from the database and from disk
+    pub invalidated: path::StorePaths,
+}
+
+impl VerifyReport {
+    /// Whether some bad paths are still valid
+    pub fn errors(&self) -> bool {
+        self.missing
+            .iter()
+            .chain(self.modified.iter())
+            .any(|v| !self.invalidated.contains(v))
+    }
+}
+
 pub trait BuildStore: WriteStore + ReadStore + Store {
     fn build_paths<'a>(
         &'a self,
@@ -67,6 +198,25 @@ pub trait BuildStore: WriteStore + ReadStore + Store {
         paths: &'a Vec<path::StorePathWithOutputs>,
     ) -> LocalFutureObj<'a, Result<MissingInfo, StoreError>>;
 
+    /// Ask the substituters about `path`, `None` if none of them has it.
+    /// There are no substituters yet, so everything missing has to be built.
+    fn query_substitutable_path_info<'a>(
+        &'a self,
+        _path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<Option<SubstitutablePathInfo>, StoreError>> {
+        LocalFutureObj::new(Box::new(async { Ok(None) }))
+    }
+
+    /// Build the outputs of `drv`, which is sent by the client instead of being read from `drv_path`.
+    /// Build failures are reported in the `BuildResult`, not as an error.
+    fn build_derivation<'a>(
+        &'a self,
+        drv_path: &'a StorePath,
+        drv: &'a crate::build::derivation::Derivation,
+        mode: BuildMode,
+        options: &'a BuildOptions,
+    ) -> LocalFutureObj<'a, Result<BuildResult, StoreError>>;
+
     fn prime_cache<'a>(
         &'a self,
         drvs: &'a Vec<path::StorePathWithOutputs>,
@@ -79,7 +229,7 @@ pub trait BuildStore: WriteStore + ReadStore + Store {
             let max_build_jobs = max_build_jobs.parse::<usize>().unwrap_or(0); // TODO: handle other cases
             drop(conf);
 
-            println!("missing: {:?}", missing);
+            debug!("missing: {:?}", missing);
 
             if missing.will_build.len() != 0 && max_build_jobs == 0
             /* getMachines() */
@@ -135,6 +285,13 @@ pub trait WriteStore: ReadStore + Store {
         info: ValidPathInfo,
     ) -> LocalFutureObj<'a, Result<ValidPathInfo, StoreError>>;
 
+    /// Add `sigs` to the signatures of the valid path `path`, known signatures are skipped
+    fn add_signatures<'a>(
+        &'a self,
+        path: &'a StorePath,
+        sigs: &'a [String],
+    ) -> LocalFutureObj<'a, Result<(), StoreError>>;
+
     fn add_temp_root<'a>(
         &'a self,
         path: &'a StorePath,
@@ -155,6 +312,23 @@ pub trait WriteStore: ReadStore + Store {
         uid: u32,
     ) -> LocalFutureObj<'a, Result<(), StoreError>>;
 
+    /// Hard-link identical files of all valid paths
+    fn optimise_store<'a>(&'a self) -> LocalFutureObj<'a, Result<OptimiseStats, StoreError>>;
+
+    /// Check that all valid paths exist and, with `check_contents`, match their NAR hash.
+    /// With `repair` bad paths without valid referrers are invalidated and deleted.
+    fn verify_store<'a>(
+        &'a self,
+        check_contents: bool,
+        repair: bool,
+    ) -> LocalFutureObj<'a, Result<VerifyReport, StoreError>>;
+
+    /// Find the paths not reachable from the roots and, depending on `options.action`, delete them
+    fn collect_garbage<'a>(
+        &'a self,
+        options: &'a crate::gc::GCOptions,
+    ) -> LocalFutureObj<'a, Result<crate::gc::GCResults, StoreError>>;
+
     fn box_clone_write(&self) -> Box<dyn WriteStore>;
 }
 
@@ -169,6 +343,90 @@ pub trait ReadStore: Store {
         path: &'a StorePath,
     ) -> LocalFutureObj<'a, Result<bool, StoreError>>;
 
+    /// The paths of `paths` which are valid, in the order of `paths`
+    fn query_valid_paths<'a>(
+        &'a self,
+        paths: &'a [StorePath],
+    ) -> LocalFutureObj<'a, Result<path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut valid = Vec::new();
+            for v in paths {
+                if self.is_valid_path(v).await? {
+                    valid.push(v.clone());
+                }
+            }
+            Ok(valid)
+        }))
+    }
+
+    fn query_all_valid_paths<'a>(
+        &'a self,
+    ) -> LocalFutureObj<'a, Result<path::StorePaths, StoreError>>;
+
+    /// The valid path with the given hash part, see `StorePath::hash_part`
+    fn query_path_from_hash_part<'a>(
+        &'a self,
+        hash_part: &'a str,
+    ) -> LocalFutureObj<'a, Result<Option<StorePath>, StoreError>>;
+
+    fn query_store_stats<'a>(&'a self) -> LocalFutureObj<'a, Result<StoreStats, StoreError>>;
+
+    /// Paths `path` refers to, including `path` itself if it refers to itself
+    fn query_references<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<path::StorePaths, StoreError>>;
+
+    /// Valid paths which refer to `path`
+    fn query_referrers<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<path::StorePaths, StoreError>>;
+
+    /// Valid derivations which have `path` as an output
+    fn query_valid_derivers<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<path::StorePaths, StoreError>>;
+
+    /// Output names of the derivation `path` mapped to their paths
+    fn query_derivation_output_map<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<path::OutputPathMap, StoreError>>;
+
+    /// Write the NAR serialisation of the valid path `path` to `sink`
+    fn nar_from_path<'a>(
+        &'a self,
+        path: &'a StorePath,
+        sink: &'a dyn crate::source::AsyncWrite,
+    ) -> LocalFutureObj<'a, Result<(), StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            if !self.is_valid_path(path).await? {
+                return Err(StoreError::InvalidPath {
+                    path: self.print_store_path(path),
+                });
+            }
+            crate::archive::dump_path(&self.print_store_path(path), sink).await?;
+            Ok(())
+        }))
+    }
+
+    fn query_derivation_outputs<'a>(
+        &'a self,
+        path: &'a StorePath,
+    ) -> LocalFutureObj<'a, Result<path::StorePaths, StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let mut outputs: path::StorePaths = self
+                .query_derivation_output_map(path)
+                .await?
+                .into_values()
+                .collect();
+            outputs.sort();
+            Ok(outputs)
+        }))
+    }
+
     fn make_text_path<'a>(
         &'a self,
         suffix: &'a str,
@@ -0,0 +1,230 @@
+//! Deduplication of identical files in the store.
+//! Every regular file is hard-linked to `.links/<nar hash>`, so identical files share an inode.
+//! The garbage collector removes entries of `.links` which have no other links left.
+
+use std::collections::HashSet;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::future::LocalFutureObj;
+use log::*;
+
+use super::StoreError;
+
+/// Counter for unique names of temporary links
+static TEMP_LINKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Work done by the `Optimiser`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OptimiseStats {
+    pub files_linked: u64,
+    pub bytes_freed: u64,
+}
+
+pub struct Optimiser {
+    store_dir: String,
+    links_dir: String,
+    /// Inodes of files which are already in `.links`
+    inodes: HashSet<u64>,
+    pub stats: OptimiseStats,
+}
+
+impl Optimiser {
+    pub fn new(store_dir: &str) -> Result<Self, StoreError> {
+        let links_dir = format!("{}/.links", store_dir);
+        std::fs::create_dir_all(&links_dir)?;
+        Ok(Self {
+            store_dir: store_dir.to_string(),
+            links_dir,
+            inodes: HashSet::new(),
+            stats: OptimiseStats::default(),
+        })
+    }
+
+    /// Remember the inodes in `.links`, files with those inodes are skipped without hashing
+    pub fn load_inodes(&mut self) -> Result<(), StoreError> {
+        for v in std::fs::read_dir(&self.links_dir)? {
+            let meta = v?.metadata()?;
+            // entries with a single link are garbage
+            if meta.nlink() > 1 {
+                self.inodes.insert(meta.ino());
+            }
+        }
+        trace!("loaded {} inodes of {}", self.inodes.len(), self.links_dir);
+        Ok(())
+    }
+
+    /// Hard-link all regular files below `path` to their entry in `.links`
+    pub fn optimise_path<'a>(
+        &'a mut self,
+        path: &'a Path,
+    ) -> LocalFutureObj<'a, Result<(), StoreError>> {
+        LocalFutureObj::new(Box::new(async move {
+            let meta = std::fs::symlink_metadata(path)?;
+
+            if meta.is_dir() {
+                for v in std::fs::read_dir(path)? {
+                    self.optimise_path(&v?.path()).await?;
+                }
+                return Ok(());
+            }
+            if !meta.is_file() {
+                return Ok(());
+            }
+            // files in the store are read-only, anything else might still change
+            if meta.mode() & 0o200 != 0 {
+                warn!("skipping suspicious writable file '{}'", path.display());
+                return Ok(());
+            }
+            if self.inodes.contains(&meta.ino()) {
+                return Ok(());
+            }
+
+            // the NAR hash includes the executable bit
+            let (hash, _) = crate::archive::hash_path(&path.to_string_lossy()).await?;
+            let link = Path::new(&self.links_dir).join(hash.to_string());
+
+            let link_meta = match std::fs::symlink_metadata(&link) {
+                Ok(v) => v,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    self.create_link(path, &link)?;
+                    self.inodes.insert(meta.ino());
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if link_meta.ino() == meta.ino() {
+                self.inodes.insert(meta.ino());
+                return Ok(());
+            }
+
+            trace!("linking '{}' to '{}'", path.display(), link.display());
+            if !self.replace_with_link(path, &link)? {
+                return Ok(());
+            }
+            self.inodes.insert(link_meta.ino());
+            self.stats.files_linked += 1;
+            self.stats.bytes_freed += meta.len();
+
+            Ok(())
+        }))
+    }
+
+    /// Make `path` the entry in `.links` for its contents
+    fn create_link(&self, path: &Path, link: &Path) -> Result<(), StoreError> {
+        match std::fs::hard_link(path, link) {
+            Ok(()) => Ok(()),
+            // another process was faster
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+            // a full directory index only disables deduplication of this file
+            Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                debug!("cannot link '{}': {}", link.display(), e);
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Atomically replace `path` by a hard link to `link`, returns false if `path` is kept
+    fn replace_with_link(&self, path: &Path, link: &Path) -> Result<bool, StoreError> {
+        let temp = format!(
+            "{}/.tmp-link-{}-{}",
+            self.store_dir,
+            std::process::id(),
+            TEMP_LINKS.fetch_add(1, Ordering::Relaxed)
+        );
+        match std::fs::hard_link(link, &temp) {
+            Ok(()) => (),
+            // the garbage collector removed the unused entry, ours replaces it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.create_link(path, link)?;
+                return Ok(false);
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EMLINK) => {
+                debug!("'{}' has the maximum number of links", link.display());
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        // store paths are read-only, the top level directory of the store is not
+        let dir = path.parent().filter(|v| *v != Path::new(&self.store_dir));
+        let mode = match dir {
+            Some(dir) => {
+                let mode = std::fs::metadata(dir)?.permissions().mode();
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode | 0o200))?;
+                Some(mode)
+            }
+            None => None,
+        };
+
+        let renamed = std::fs::rename(&temp, path);
+        if let (Some(dir), Some(mode)) = (dir, mode) {
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))?;
+        }
+        if let Err(e) = renamed {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e.into());
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OptimiseStats, Optimiser};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    fn read_only(path: &std::path::Path, mode: u32) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn optimise() {
+        let store = std::env::temp_dir().join(format!("nix-test-optimise-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&store);
+        let foo = store.join("ffffffffffffffffffffffffffffffff-foo");
+        let bar = store.join("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bar");
+        std::fs::create_dir_all(&foo).unwrap();
+        std::fs::write(foo.join("a"), "same").unwrap();
+        std::fs::write(foo.join("exe"), "same").unwrap();
+        read_only(&foo.join("a"), 0o444);
+        read_only(&foo.join("exe"), 0o555);
+        read_only(&foo, 0o555);
+        std::fs::write(&bar, "same").unwrap();
+        read_only(&bar, 0o444);
+
+        let mut optimiser = Optimiser::new(store.to_str().unwrap()).unwrap();
+        optimiser.optimise_path(&foo).await.unwrap();
+        optimiser.optimise_path(&bar).await.unwrap();
+        assert_eq!(
+            optimiser.stats,
+            OptimiseStats {
+                files_linked: 1,
+                bytes_freed: 4
+            }
+        );
+
+        let ino = |v: &std::path::Path| std::fs::metadata(v).unwrap().ino();
+        assert_eq!(ino(&foo.join("a")), ino(&bar));
+        // different executable bit, different NAR
+        assert_ne!(ino(&foo.join("a")), ino(&foo.join("exe")));
+        assert_eq!(
+            std::fs::metadata(&foo).unwrap().permissions().mode() & 0o777,
+            0o555
+        );
+        assert_eq!(std::fs::read_dir(store.join(".links")).unwrap().count(), 2);
+
+        // a second pass has nothing to do
+        let mut optimiser = Optimiser::new(store.to_str().unwrap()).unwrap();
+        optimiser.load_inodes().unwrap();
+        optimiser.optimise_path(&foo).await.unwrap();
+        optimiser.optimise_path(&bar).await.unwrap();
+        assert_eq!(optimiser.stats, OptimiseStats::default());
+
+        read_only(&foo, 0o755);
+        std::fs::remove_dir_all(&store).unwrap();
+    }
+}
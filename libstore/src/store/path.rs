@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use log::*;
 
@@ -12,6 +12,14 @@ pub const STORE_PATH: &str = "/nix/store"; // TODO: uses non hardcoded thingi
 pub type StorePaths = Vec<StorePath>;
 pub type OutputPathMap = HashMap<String, StorePath>;
 
+/// Characters of the base32 encoding Nix uses for hash parts
+const BASE32_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Check if `hash_part` could be the hash part of a store path
+pub fn is_valid_hash_part(hash_part: &str) -> bool {
+    hash_part.len() == HASHLEN as usize && hash_part.chars().all(|v| BASE32_CHARS.contains(v))
+}
+
 #[derive(Debug, Clone, Hash)]
 pub struct StorePath {
     base_name: String,
@@ -117,13 +125,20 @@ impl StorePathWithOutputs {
         Self { path, outputs }
     }
 
-    /*pub fn to_string<T: super::Store>(&self, store: &T) -> String {
-        if self.outputs.len() == 0 {
-            self.path.to_string(store)
+    /// Whether the output `name` is requested, no outputs means all of them
+    pub fn wants_output(&self, name: &str) -> bool {
+        self.outputs.is_empty() || self.outputs.iter().any(|v| v == name)
+    }
+}
+
+impl fmt::Display for StorePathWithOutputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.outputs.is_empty() {
+            write!(f, "{}", self.path)
         } else {
-            format!("{}!{}", self.path.to_string(store), self.outputs.join(","))
+            write!(f, "{}!{}", self.path, self.outputs.join(","))
         }
-    }*/
+    }
 }
 
 impl PartialEq<StorePath> for StorePathWithOutputs {
@@ -132,6 +147,36 @@ impl PartialEq<StorePath> for StorePathWithOutputs {
     }
 }
 
+/// `paths` ordered so every path comes after the paths of `paths` it refers to
+pub fn references_first(
+    paths: &[StorePath],
+    references: &HashMap<StorePath, Vec<StorePath>>,
+) -> Vec<StorePath> {
+    fn visit(
+        path: &StorePath,
+        references: &HashMap<StorePath, Vec<StorePath>>,
+        visited: &mut HashSet<StorePath>,
+        sorted: &mut Vec<StorePath>,
+    ) {
+        if !visited.insert(path.clone()) {
+            return;
+        }
+        for v in references.get(path).into_iter().flatten() {
+            if references.contains_key(v) {
+                visit(v, references, visited, sorted);
+            }
+        }
+        sorted.push(path.clone());
+    }
+
+    let mut visited = HashSet::new();
+    let mut sorted = Vec::new();
+    for v in paths {
+        visit(v, references, &mut visited, &mut sorted);
+    }
+    sorted
+}
+
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
@@ -187,4 +232,21 @@ mod test {
         assert_eq!(paths, StorePath::new(DUMMY).unwrap());
         assert_eq!(paths, paths_2);
     }
+
+    #[test]
+    fn references_first() {
+        let path = |v: &str| StorePath::new(v).unwrap();
+        const FOO: &str = "ffffffffffffffffffffffffffffffff-foo";
+        const BAR: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bar";
+        const BAZ: &str = "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz-baz";
+        let mut references = std::collections::HashMap::new();
+        references.insert(path(FOO), vec![path(BAR), path(FOO)]);
+        references.insert(path(BAR), vec![path(BAZ)]);
+        references.insert(path(BAZ), vec![]);
+
+        assert_eq!(
+            super::references_first(&[path(FOO), path(BAZ), path(BAR)], &references),
+            vec![path(BAZ), path(BAR), path(FOO)]
+        );
+    }
 }
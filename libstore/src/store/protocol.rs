@@ -1,5 +1,5 @@
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WorkerOp {
     WopInvalidRequest = 0, // Invalid Request
     WopIsValidPath = 1,
@@ -39,6 +39,7 @@ pub enum WorkerOp {
     WopNarFromPath = 38,
     WopAddToStoreNar = 39,
     WopQueryMissing = 40,
+    WopQueryDerivationOutputMap = 41,
 }
 
 impl From<u32> for WorkerOp {
@@ -82,6 +83,7 @@ impl From<u32> for WorkerOp {
             38 => WorkerOp::WopNarFromPath,
             39 => WorkerOp::WopAddToStoreNar,
             40 => WorkerOp::WopQueryMissing,
+            41 => WorkerOp::WopQueryDerivationOutputMap,
 
             _ => WorkerOp::WopInvalidRequest,
         }
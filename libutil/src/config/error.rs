@@ -26,6 +26,7 @@ custom_error! {
     pub Error
         ParseError{source: ParseError} = "Parsing Error: {source}",
         Io{source: std::io::Error} = "IoError: {source}",
+        InvalidValue{name: String, value: String} = "invalid value for {name}: '{value}'",
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
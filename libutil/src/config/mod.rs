@@ -20,10 +20,11 @@
 
 pub mod error;
 
-use crate::config::error::{ParseError, ParseResult, Result};
+use crate::config::error::{Error, ParseError, ParseResult, Result};
 use log::{trace, warn};
 use serde::de::{DeserializeSeed, MapAccess, SeqAccess, Visitor};
 use serde::{de, forward_to_deserialize_any, Deserialize, Serialize};
+use std::collections::HashSet;
 use std::ops::{AddAssign, MulAssign, Neg};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -39,6 +40,23 @@ pub struct NixConfig {
     #[serde(default = "default_socket_path")]
     pub nix_daemon_socket_file: String, // path to the nix daemon socket path
 
+    #[serde(default = "default_daemon_shutdown_timeout")]
+    pub daemon_shutdown_timeout: usize, // Seconds the daemon waits for running operations to finish when shutting down before cancelling them.
+
+    pub daemon_worker_processes: bool, // Whether the daemon serves every client in its own worker process, so a crash in one session does not affect the others.
+
+    pub daemon_admin_socket_file: String, // Path of the socket reporting the status of the daemon as JSON. Only root can connect. Disabled if empty.
+
+    pub daemon_audit_log: String, // Path of the audit log, which gets one JSON line per op changing the store. Disabled if empty.
+    #[serde(default = "default_daemon_audit_log_max_size")]
+    pub daemon_audit_log_max_size: usize, // Size in bytes after which the audit log is rotated. 0 disables rotation.
+    #[serde(default = "default_daemon_audit_log_keep")]
+    pub daemon_audit_log_keep: usize, // Number of rotated audit logs to keep.
+
+    pub daemon_max_connections_per_user: usize, // Maximum number of concurrent connections of a single user. 0 means unlimited.
+    pub daemon_max_builds_per_user: usize, // Maximum number of concurrent build ops of a single user. 0 means unlimited.
+    pub daemon_max_upload_size: u64, // Maximum size in bytes of a NAR an untrusted user can upload in a single op. 0 means unlimited.
+
     pub keep_failed: bool, // Whether to keep temporary directories of failed builds.
     pub keep_going: bool,  // Whether to keep building derivations when another build fails.
     #[serde(alias = "build-fallback")]
@@ -241,11 +259,23 @@ pub struct NixConfig {
 
 impl NixConfig {
     pub fn parse_file(file: &std::path::Path) -> Result<Self> {
+        Self::parse_file_with_overrides(file, &[])
+    }
+
+    /// Parse `file` and apply the `name = value` pairs in `overrides` on top of it,
+    /// as if they were appended to the file.
+    pub fn parse_file_with_overrides(
+        file: &std::path::Path,
+        overrides: &[(String, String)],
+    ) -> Result<Self> {
         let old_dir = std::env::current_dir()?;
         let base_path = file.parent().unwrap();
         std::env::set_current_dir(&base_path)?;
 
-        let config_text = std::fs::read_to_string(file)?;
+        let mut config_text = std::fs::read_to_string(file)?;
+        for (name, value) in overrides {
+            config_text.push_str(&format!("\n{} = {}\n", name, value));
+        }
         let config_text = Self::pre_text(config_text)?;
         let config: NixConfig = crate::config::from_str(&config_text)?;
 
@@ -258,8 +288,31 @@ impl NixConfig {
         Ok(config)
     }
 
+    /// Check settings the parser accepts as plain strings
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |name: &str, value: &str| Error::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+
+        if self.store.is_empty() {
+            return Err(invalid("store", &self.store));
+        }
+        if self.nix_daemon_socket_file.is_empty() {
+            return Err(invalid(
+                "nix-daemon-socket-file",
+                &self.nix_daemon_socket_file,
+            ));
+        }
+        if self.max_jobs != "auto" && self.max_jobs.parse::<usize>().is_err() {
+            return Err(invalid("max-jobs", &self.max_jobs));
+        }
+
+        Ok(())
+    }
+
     pub fn pre_text(text: String) -> ParseResult<String> {
-        let mut end_text = String::new();
+        let mut lines: Vec<&str> = Vec::new();
         for line in text.lines() {
             if line.starts_with('#') {
             } else if line.is_empty() {
@@ -271,44 +324,42 @@ impl NixConfig {
                 warn!("implement parsing of !include: {}", line);
             } else {
                 // TODO parse commands at the end
-                end_text.push_str(&format!("{}\n", line));
+                // a later setting overrides an earlier one
+                let key = line.split_whitespace().next();
+                lines.retain(|v| v.split_whitespace().next() != key);
+                lines.push(line);
             }
         }
+
+        let mut end_text = String::new();
+        for line in lines {
+            end_text.push_str(&format!("{}\n", line));
+        }
         Ok(end_text)
     }
 
-    pub fn is_trusted_user(&self, user: &str, group: &str) -> bool {
-        for v in &self.trusted_users {
-            if v.starts_with('@') {
-                if &v[1..] == group {
-                    return true;
-                }
-            }
-            if v == "*" {
-                return true;
-            } else if v == user {
-                return true;
-            }
-        }
-        false
+    /// `groups` are all groups the user is a member of, primary and supplementary
+    pub fn is_trusted_user(&self, user: &str, groups: &HashSet<String>) -> bool {
+        matches_user(&self.trusted_users, user, groups)
     }
-    pub fn is_allowed_user(&self, user: &str, group: &str) -> bool {
-        for v in &self.allowed_users {
-            if v.starts_with('@') {
-                if &v[1..] == group {
-                    return true;
-                }
-            }
-            if v == "*" {
-                return true;
-            } else if v == user {
-                return true;
-            }
-        }
-        false
+
+    /// `groups` are all groups the user is a member of, primary and supplementary
+    pub fn is_allowed_user(&self, user: &str, groups: &HashSet<String>) -> bool {
+        matches_user(&self.allowed_users, user, groups)
     }
 }
 
+/// Check if `user` is in a list of user names, `@group`s and `*`
+fn matches_user(list: &[String], user: &str, groups: &HashSet<String>) -> bool {
+    list.iter().any(|v| {
+        if let Some(group) = v.strip_prefix('@') {
+            groups.contains(group)
+        } else {
+            v == "*" || v == user
+        }
+    })
+}
+
 fn default_store() -> String {
     use std::env::var;
     //var("NIX_STORE_DIR").unwrap_or_else(|_| var("NIX_STORE").unwrap_or(String::from("auto")))
@@ -323,6 +374,18 @@ fn default_socket_path() -> String {
     String::from("/nix/var/nix/daemon-socket/socket")
 }
 
+fn default_daemon_shutdown_timeout() -> usize {
+    30
+}
+
+fn default_daemon_audit_log_max_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_daemon_audit_log_keep() -> usize {
+    5
+}
+
 fn default_max_jobs() -> String {
     String::from("1")
 }
@@ -475,6 +538,7 @@ impl<'de> Deserializer<'de> {
                 return Err(ParseError::ExpectedInteger {});
             }
         };
+        self.input = &self.input[1..];
         loop {
             match self.input.chars().next() {
                 Some(ch @ '0'..='9') => {
@@ -698,3 +762,75 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         tuple bytes byte_buf option unit unit_struct newtype_struct tuple_struct enum f32 f64
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::NixConfig;
+
+    #[test]
+    fn overrides() {
+        let path = std::env::temp_dir().join(format!("nix-test-config-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# comment\nstore = /nix/store\nmax-jobs = 4\ncores = 2\ncores = 3\n",
+        )
+        .unwrap();
+
+        let config = NixConfig::parse_file(&path).unwrap();
+        assert_eq!(config.store, "/nix/store");
+        assert_eq!(config.max_jobs, "4");
+        assert_eq!(config.cores, 3);
+
+        let overrides = vec![
+            ("max-jobs".to_string(), "8".to_string()),
+            ("store".to_string(), "file:///tmp/store".to_string()),
+        ];
+        let config = NixConfig::parse_file_with_overrides(&path, &overrides).unwrap();
+        assert_eq!(config.store, "file:///tmp/store");
+        assert_eq!(config.max_jobs, "8");
+        assert_eq!(config.cores, 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unsigned() {
+        // the first digit used to be read twice
+        let config: NixConfig = super::from_str("cores = 7\nmin-free = 1024\n").unwrap();
+        assert_eq!(config.cores, 7);
+        assert_eq!(config.min_free, 1024);
+    }
+
+    #[test]
+    fn validate() {
+        let mut config = NixConfig {
+            store: "/nix/store".to_string(),
+            nix_daemon_socket_file: "/nix/var/nix/daemon-socket/socket".to_string(),
+            max_jobs: "auto".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.max_jobs = "4".to_string();
+        assert!(config.validate().is_ok());
+
+        config.max_jobs = "many".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn trusted_users() {
+        let config = NixConfig {
+            trusted_users: vec!["root".to_string(), "@wheel".to_string()],
+            allowed_users: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let groups = |v: &[&str]| v.iter().map(|v| v.to_string()).collect();
+
+        assert!(config.is_trusted_user("root", &groups(&["root"])));
+        assert!(config.is_trusted_user("alice", &groups(&["users", "wheel"])));
+        assert!(!config.is_trusted_user("bob", &groups(&["users"])));
+        assert!(!config.is_trusted_user("wheel", &groups(&[])));
+        assert!(config.is_allowed_user("bob", &groups(&[])));
+    }
+}